@@ -4,6 +4,7 @@ mod extension_host_proxy;
 mod extension_manifest;
 mod types;
 
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -167,6 +168,18 @@ pub trait Extension: Send + Sync + 'static {
     ) -> Result<DebugRequest>;
 }
 
+/// The oldest version of the extension API that Zed is able to load.
+pub const MIN_EXTENSION_API_VERSION: SemanticVersion = SemanticVersion::new(0, 0, 1);
+
+/// The newest released version of the extension API, which matches the latest version of the
+/// `zed_extension_api` crate.
+pub const MAX_EXTENSION_API_VERSION: SemanticVersion = SemanticVersion::new(0, 6, 0);
+
+/// Returns the Wasm API version range that is supported by the Wasm host.
+pub fn wasm_api_version_range() -> RangeInclusive<SemanticVersion> {
+    MIN_EXTENSION_API_VERSION..=MAX_EXTENSION_API_VERSION
+}
+
 pub fn parse_wasm_extension_version(
     extension_id: &str,
     wasm_bytes: &[u8],
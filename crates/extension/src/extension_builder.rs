@@ -1,60 +1,993 @@
+mod download;
+mod git;
+mod grammar;
+mod rust;
+mod sandbox;
+mod validate;
+
 use crate::{
-    ExtensionLibraryKind, ExtensionManifest, GrammarManifestEntry, parse_wasm_extension_version,
+    ExtensionLibraryKind, ExtensionManifest, GrammarArchive, GrammarManifestEntry,
+    OldExtensionManifest, PostBuildManifestEntry, SchemaVersion, manifest_from_old_manifest,
+    parse_wasm_extension_version, wasm_api_version_range,
 };
 use anyhow::{Context as _, Result, bail};
-use async_compression::futures::bufread::GzipDecoder;
+use async_compression::futures::{bufread::GzipDecoder, write::GzipEncoder};
 use async_tar::Archive;
-use futures::io::BufReader;
+use base64::{Engine as _, prelude::BASE64_STANDARD};
+use collections::{BTreeMap, HashMap};
+use futures::{
+    AsyncReadExt as _, AsyncWriteExt as _, StreamExt as _, TryStreamExt as _,
+    io::{AllowStdIo, BufReader},
+    stream,
+};
 use heck::ToSnakeCase;
-use http_client::{self, AsyncBody, HttpClient};
-use serde::Deserialize;
+use http_client::{self, AsyncBody, HttpClient, Url};
+use language::LanguageConfig;
+use parking_lot::Mutex;
+use rust::rust_target;
+use sandbox::{
+    SANDBOX_ENV_VARS, Sandbox, collect_sandbox_artifacts, copy_extension_source,
+    remove_dir_if_exists,
+};
+use semantic_version::SemanticVersion;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha384, Sha512};
 use std::{
-    env, fs, mem,
-    path::{Path, PathBuf},
-    process::Stdio,
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, BufRead as _, Read as _},
+    mem,
+    ops::RangeInclusive,
+    path::{Component, Path, PathBuf},
+    process::{Command, Output, Stdio},
     str::FromStr,
     sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use wasm_encoder::{ComponentSectionId, Encode as _, RawSection, Section as _};
-use wasmparser::Parser;
-
-/// Currently, we compile with Rust's `wasm32-wasip2` target, which works with WASI `preview2` and the component model.
-const RUST_TARGET: &str = "wasm32-wasip2";
-
-/// Compiling Tree-sitter parsers from C to WASM requires Clang 17, and a WASM build of libc
-/// and clang's runtime library. The `wasi-sdk` provides these binaries.
-///
-/// Once Clang 17 and its wasm target are available via system package managers, we won't need
-/// to download this.
-const WASI_SDK_URL: &str = "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-25/";
-const WASI_SDK_ASSET_NAME: Option<&str> = if cfg!(all(target_os = "macos", target_arch = "x86_64"))
-{
-    Some("wasi-sdk-25.0-x86_64-macos.tar.gz")
-} else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
-    Some("wasi-sdk-25.0-arm64-macos.tar.gz")
-} else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
-    Some("wasi-sdk-25.0-x86_64-linux.tar.gz")
-} else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
-    Some("wasi-sdk-25.0-arm64-linux.tar.gz")
-} else if cfg!(all(target_os = "freebsd", target_arch = "x86_64")) {
-    Some("wasi-sdk-25.0-x86_64-linux.tar.gz")
-} else if cfg!(all(target_os = "freebsd", target_arch = "aarch64")) {
-    Some("wasi-sdk-25.0-arm64-linux.tar.gz")
-} else if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
-    Some("wasi-sdk-25.0-x86_64-windows.tar.gz")
-} else {
-    None
+use theme::{IconThemeContent, IconThemeFamilyContent, ThemeFamilyContent};
+use validate::{
+    validate_icon_themes, validate_languages, validate_prebuilt_extension, validate_snippets,
+    validate_themes, verify_language_grammars, write_normalized_themes,
 };
+use wasm_encoder::{ComponentSectionId, Encode as _, RawSection, Section as _};
+use wasmparser::{Parser, Validator, WasmFeatures};
+
+/// The file name of the wasm that all grammars are combined into, which is written to the
+/// extension directory unless [`CompileExtensionOptions::grammar_output_dir`] is set.
+const COMBINED_GRAMMARS_WASM_FILE_NAME: &str = "grammars.wasm";
+
+/// The default for [`ExtensionBuilder::max_grammars`]. This is far more than any real extension
+/// declares.
+pub const DEFAULT_MAX_GRAMMARS: usize = 256;
+
+/// The functions that Tree-sitter's wasm store provides to grammars, from its bundled subset of
+/// the C standard library. This is the default for [`ExtensionBuilder::allowed_grammar_imports`].
+pub const DEFAULT_ALLOWED_GRAMMAR_IMPORTS: &[&str] = &[
+    "abort",
+    "calloc",
+    "free",
+    "iswalnum",
+    "iswalpha",
+    "iswblank",
+    "iswdigit",
+    "iswlower",
+    "iswspace",
+    "iswupper",
+    "iswxdigit",
+    "malloc",
+    "memchr",
+    "memcmp",
+    "memcpy",
+    "memmove",
+    "memset",
+    "realloc",
+    "strcmp",
+    "strlen",
+    "strncat",
+    "strncmp",
+    "strncpy",
+    "towlower",
+    "towupper",
+];
+
+/// The default for [`ExtensionBuilder::clang_retries`].
+const DEFAULT_CLANG_RETRIES: u32 = 2;
+
+/// How long an extension's post-build command may run for before it is killed, unless
+/// [`CompileExtensionOptions::post_build_timeout`] is set.
+const DEFAULT_POST_BUILD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The name of the custom section that an extension may embed its manifest into, as TOML.
+const MANIFEST_SECTION_NAME: &str = "zed:manifest";
+
+/// The name of the custom section that [`BuildInfo`] is stored in.
+const BUILD_INFO_SECTION_NAME: &str = "zed:build-info";
+
+/// The Tree-sitter ABI versions of grammars that Zed is able to load.
+pub const SUPPORTED_GRAMMAR_ABI_VERSIONS: RangeInclusive<u32> = 13..=15;
+
+/// The WebAssembly features that Zed's Wasmtime engine supports when loading extensions.
+pub const HOST_WASM_FEATURES: WasmFeatures = WasmFeatures::WASM2
+    .union(WasmFeatures::RELAXED_SIMD)
+    .union(WasmFeatures::TAIL_CALL)
+    .union(WasmFeatures::EXTENDED_CONST)
+    .union(WasmFeatures::MULTI_MEMORY)
+    .union(WasmFeatures::COMPONENT_MODEL);
+
+/// The targets that grammars are known to compile correctly for with the `wasi-sdk` Clang.
+pub const SUPPORTED_GRAMMAR_CLANG_TARGETS: &[&str] =
+    &["wasm32-wasi", "wasm32-wasip1", "wasm32-wasip2"];
+const DEFAULT_GRAMMAR_CLANG_TARGET: &str = "wasm32-wasi";
 
 pub struct ExtensionBuilder {
     cache_dir: PathBuf,
     pub http: Arc<dyn HttpClient>,
+    /// Tokens used to authenticate when fetching grammar repositories.
+    pub git_credentials: Vec<GitCredential>,
+    /// The depth of the history to fetch when checking out grammar repositories, or `None` to
+    /// fetch the full history. If a shallow fetch fails, the full history is fetched instead.
+    pub git_fetch_depth: Option<u32>,
+    /// Rules for fetching grammar repositories from mirrors rather than from the URLs in their
+    /// manifests. Checkouts keep the manifest's URL as their `origin`.
+    pub git_url_rewrites: Vec<GitUrlRewrite>,
+    /// URLs that grammar repositories must be within, e.g. `https://github.com/` or
+    /// `https://github.com/tree-sitter`, to be fetched. A repository is within a URL when their
+    /// schemes, hosts and ports are the same and the URL's path is a prefix of the repository's
+    /// path, ending at a `/`. When empty, repositories at any URL are allowed.
+    pub allowed_grammar_repositories: Vec<String>,
+    /// URLs of grammar repositories that must not be fetched, matched like
+    /// [`Self::allowed_grammar_repositories`]. These take precedence over the allowed URLs.
+    pub denied_grammar_repositories: Vec<String>,
+    /// Whether to check out each grammar into a directory named after both the grammar and a hash
+    /// of its repository URL, rather than after the grammar alone. A grammar whose repository
+    /// changes then gets a fresh checkout, instead of conflicting with the old one.
+    pub namespace_grammar_checkouts: bool,
+    /// The directories to use as the `PATH` for the tools run during a build (`cargo`, `git`,
+    /// etc.). When unset, the tools inherit the `PATH` of the current process.
+    pub tool_dirs: Option<Vec<PathBuf>>,
+    /// The target triple passed to Clang when compiling grammars. Must be one of
+    /// [`SUPPORTED_GRAMMAR_CLANG_TARGETS`].
+    pub grammar_clang_target: String,
+    /// The WASI preview 1 adapter (`wasi_snapshot_preview1.reactor.wasm`) used to encode
+    /// extensions built with [`CompileExtensionOptions::wasip1`] into components.
+    pub wasip1_adapter_path: Option<PathBuf>,
+    /// The Clang to compile grammars with, instead of the one from the downloaded `wasi-sdk`.
+    /// It must be able to link for [`Self::grammar_clang_target`]. Both `clang` and `clang-cl`
+    /// are supported; `clang-cl` is switched to the GCC-style driver that the build relies on.
+    pub clang_path: Option<PathBuf>,
+    /// The linker that Clang links grammars with, instead of its default `wasm-ld`. This may
+    /// be a path or a program on the `PATH`, and must be a `wasm-ld` compatible linker.
+    pub grammar_linker: Option<PathBuf>,
+    /// The WASI sysroot to compile grammars against when using [`Self::clang_path`]. When
+    /// unset, the `WASI_LIBC_PATH` environment variable is used, then a sysroot installed in a
+    /// common location, and failing that, a sysroot matching the `wasi-sdk` version is downloaded
+    /// into the cache directory.
+    pub wasi_sysroot_path: Option<PathBuf>,
+    /// The expected SHA-256 checksum of the WASI sysroot archive, overriding the checksum pinned
+    /// for the `wasi-sdk` version. A downloaded sysroot is always verified against one of them.
+    pub wasi_sysroot_sha256: Option<String>,
+    /// The expected SHA-256 checksum of the `wasi-sdk` release asset for this platform,
+    /// overriding the checksum pinned for the `wasi-sdk` version. A downloaded `wasi-sdk` is
+    /// always verified against one of them.
+    pub wasi_sdk_sha256: Option<String>,
+    /// Whether to download a pinned release of the Tree-sitter CLI into the cache directory for
+    /// generating grammar parsers from their `grammar.json`, rather than running the
+    /// `tree-sitter` on the `PATH`.
+    pub download_tree_sitter_cli: bool,
+    /// The expected SHA-256 checksum of the downloaded Tree-sitter CLI release asset for this
+    /// platform, overriding the checksum pinned for the Tree-sitter version. A downloaded CLI is
+    /// always verified against one of them.
+    pub tree_sitter_cli_sha256: Option<String>,
+    /// The maximum size of a compiled `extension.wasm` in bytes. Builds that exceed it fail.
+    pub max_extension_wasm_size: Option<u64>,
+    /// The maximum size of each grammar's wasm in bytes, whether compiled, downloaded or
+    /// committed. Builds that exceed it fail.
+    pub max_grammar_wasm_size: Option<u64>,
+    /// The maximum number of grammars that an extension may declare. Builds of extensions that
+    /// declare more fail before any grammar is fetched.
+    pub max_grammars: usize,
+    /// The number of times to retry a Clang invocation that fails without compiling anything,
+    /// because Clang couldn't be started or was killed by a signal. Compile errors are never
+    /// retried.
+    pub clang_retries: u32,
+    /// The host functions that compiled grammars may import. A grammar whose scanner calls any
+    /// other function, such as one from the WASI sysroot's libc that Tree-sitter doesn't
+    /// provide, would fail to load, so it is reported as a grammar warning.
+    pub allowed_grammar_imports: Vec<String>,
+    /// Whether to build each extension in a fresh copy of its source in the cache directory,
+    /// running commands with only [`SANDBOX_ENV_VARS`] from the environment, and to then collect
+    /// just the expected artifacts into [`CompileOutput::artifacts_dir`]. This keeps builds of
+    /// third-party extensions away from the extension directory and from secrets in the
+    /// environment.
+    ///
+    /// This is not a security boundary: build scripts and procedural macros still run with the
+    /// permissions of the current user, so hosted builds should also run in a container.
+    pub sandbox_builds: bool,
+    /// Runs the subprocesses (`cargo`, `clang`, `git`, etc.) used during a build. Tests can
+    /// replace this to avoid depending on a real toolchain.
+    pub command_runner: Arc<dyn CommandRunner>,
+}
+
+/// Runs the subprocesses used while building extensions.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `command` to completion, capturing its output.
+    fn output(&self, command: &mut Command) -> io::Result<Output>;
+
+    /// Runs `command` to completion like [`Self::output`], calling `on_stdout_line` with each
+    /// line of its standard output.
+    ///
+    /// By default, the lines are only passed along once the command has exited.
+    fn output_with_stdout_lines(
+        &self,
+        command: &mut Command,
+        on_stdout_line: &mut dyn FnMut(&str),
+    ) -> io::Result<Output> {
+        let output = self.output(command)?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            on_stdout_line(line);
+        }
+        Ok(output)
+    }
+
+    /// Runs `command` to completion like [`Self::output`], killing it and returning an error of
+    /// kind [`io::ErrorKind::TimedOut`] if it is still running after `timeout`.
+    ///
+    /// By default, the timeout is not enforced.
+    fn output_with_timeout(&self, command: &mut Command, _timeout: Duration) -> io::Result<Output> {
+        self.output(command)
+    }
+}
+
+/// A [`CommandRunner`] that runs commands as real subprocesses.
+pub struct RealCommandRunner;
+
+impl CommandRunner for RealCommandRunner {
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        command.output()
+    }
+
+    fn output_with_stdout_lines(
+        &self,
+        command: &mut Command,
+        on_stdout_line: &mut dyn FnMut(&str),
+    ) -> io::Result<Output> {
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read stderr on another thread, so that the child can't block on a full stderr pipe
+        // while we wait for it to write to stdout.
+        let stderr_thread = child.stderr.take().map(|mut stderr| {
+            std::thread::spawn(move || {
+                let mut buffer = Vec::new();
+                stderr.read_to_end(&mut buffer).map(|_| buffer)
+            })
+        });
+
+        let mut stdout = Vec::new();
+        if let Some(child_stdout) = child.stdout.take() {
+            for line in io::BufReader::new(child_stdout).split(b'\n') {
+                let line = line?;
+                on_stdout_line(&String::from_utf8_lossy(&line));
+                stdout.extend(line);
+                stdout.push(b'\n');
+            }
+        }
+
+        let stderr = match stderr_thread {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| io::Error::other("failed to read stderr"))??,
+            None => Vec::new(),
+        };
+        Ok(Output {
+            status: child.wait()?,
+            stdout,
+            stderr,
+        })
+    }
+
+    fn output_with_timeout(&self, command: &mut Command, timeout: Duration) -> io::Result<Output> {
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read both pipes on other threads, so that the child can't block on a full pipe while
+        // we wait for it to exit.
+        let read_pipe = |pipe: Option<Box<dyn io::Read + Send>>| {
+            std::thread::spawn(move || {
+                let mut buffer = Vec::new();
+                if let Some(mut pipe) = pipe {
+                    pipe.read_to_end(&mut buffer)?;
+                }
+                io::Result::Ok(buffer)
+            })
+        };
+        let stdout_thread = read_pipe(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+        let stderr_thread = read_pipe(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+        let deadline = Instant::now() + timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("command did not exit within {}s", timeout.as_secs()),
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        };
+
+        let join = |thread: std::thread::JoinHandle<io::Result<Vec<u8>>>| {
+            thread
+                .join()
+                .map_err(|_| io::Error::other("failed to read command output"))?
+        };
+        Ok(Output {
+            status,
+            stdout: join(stdout_thread)?,
+            stderr: join(stderr_thread)?,
+        })
+    }
+}
+
+/// A token used to authenticate when fetching grammar repositories over HTTPS.
+#[derive(Clone)]
+pub struct GitCredential {
+    /// The prefix of the repository URLs that this token applies to, e.g.
+    /// `https://github.com/my-org/`. When several credentials match a URL, the one with
+    /// the longest prefix is used.
+    pub url_prefix: String,
+    /// The username to authenticate with. When unset, `x-access-token` is used for repositories
+    /// on `github.com`, and other hosts require it to be set.
+    pub username: Option<String>,
+    pub token: String,
+}
+
+/// A rule for fetching grammar repositories from a mirror, by replacing a prefix of their URLs.
+#[derive(Clone, Debug)]
+pub struct GitUrlRewrite {
+    /// The prefix of the repository URLs to rewrite, e.g. `https://github.com/`. When several
+    /// rules match a URL, the one with the longest prefix is used.
+    pub from_prefix: String,
+    /// The prefix to replace it with, e.g. `https://git-mirror.example.com/github/`.
+    pub to_prefix: String,
 }
 
+#[derive(Clone, Default)]
 pub struct CompileExtensionOptions {
     pub release: bool,
+    /// The Rust toolchain to build the extension with (e.g., `nightly`), passed to `cargo` as
+    /// `+<toolchain>`.
+    ///
+    /// When unset, the toolchain is resolved by `rustup`, which respects any `rust-toolchain.toml`
+    /// in the extension directory.
+    pub rust_toolchain: Option<String>,
+    /// The number of parallel jobs that `cargo` may run while building the extension's Rust
+    /// crate. When unset, `cargo` uses its own default.
+    pub jobs: Option<usize>,
+    /// Whether to build the extension's Rust crate for `wasm32-wasip1` rather than
+    /// `wasm32-wasip2`, encoding the resulting core module into a component with
+    /// [`ExtensionBuilder::wasip1_adapter_path`]. This requires `wasm-tools` to be installed.
+    pub wasip1: bool,
+    /// Whether to build the extension's Rust crate with `--locked`, so that its dependencies
+    /// are resolved exactly as recorded in its `Cargo.lock`. If the extension doesn't have a
+    /// `Cargo.lock`, one is generated before building.
+    pub locked: bool,
+    /// A `Cargo.lock` to build the extension with when `locked` is set, in place of the
+    /// extension's own.
+    pub cargo_lock_path: Option<PathBuf>,
+    /// Whether to fail the build if `cargo` emits any warnings for the extension's Rust library.
+    pub deny_warnings: bool,
+    /// The WebAssembly features to enable or disable when building the extension's Rust library,
+    /// such as `+simd128` or `-bulk-memory`, passed to `rustc` as `-C target-feature`.
+    pub rust_target_features: Vec<String>,
+    /// The phase of a profile-guided optimization build to build the extension's Rust library
+    /// for, if any.
+    pub pgo: Option<PgoPhase>,
+    /// Whether to fail the build if the extension's `extension.wasm` uses WebAssembly features
+    /// outside of [`HOST_WASM_FEATURES`], rather than only warning about it.
+    pub deny_unsupported_wasm_features: bool,
+    /// Whether to fail the build if Clang emits any warnings while compiling a grammar.
+    pub deny_grammar_warnings: bool,
+    /// Whether to collect the diagnostics emitted by `rustc` and Clang into
+    /// [`CompileOutput::diagnostics`]. Failed builds always carry their diagnostics, which can be
+    /// retrieved with [`CompileDiagnostics::from_error`].
+    pub collect_diagnostics: bool,
+    /// Whether to use grammar wasm files that already exist in the extension's `grammars`
+    /// directory, instead of checking out and compiling those grammars.
+    ///
+    /// This lets extensions that commit their compiled grammars be built without a C toolchain,
+    /// at the cost of reproducibility: nothing checks that a committed wasm file actually
+    /// matches the grammar's declared repository and revision.
+    pub skip_committed_grammars: bool,
+    /// The file name, within the extension directory, to write the compiled Rust library to.
+    /// Defaults to `extension.wasm`, which is where Zed loads it from.
+    pub extension_wasm_file_name: Option<String>,
+    /// The directory to write compiled grammar wasms to, including the combined grammar wasm,
+    /// instead of the extension's `grammars` directory. Relative paths are resolved against the
+    /// extension directory.
+    pub grammar_output_dir: Option<PathBuf>,
+    /// Whether to reuse a previously built `extension.wasm` when the extension's Rust sources,
+    /// `Cargo.toml`, `Cargo.lock` and `.cargo/config.toml` are identical to those of an earlier
+    /// build with the same options, skipping `cargo` entirely. Cached builds are stored in the
+    /// builder's cache directory.
+    ///
+    /// Warnings are not reported for builds that are served from the cache. Path dependencies
+    /// outside of the extension's `src` directory aren't part of the key, so extensions that
+    /// have them shouldn't be built with this.
+    pub cache_rust_artifacts: bool,
+    /// Whether to reuse a previously compiled grammar wasm when the grammar's C sources and
+    /// headers, and the Clang arguments used to compile them, are identical to those of an
+    /// earlier build, regardless of the revision they came from. Cached grammars are stored in
+    /// the builder's cache directory.
+    ///
+    /// Warnings are not reported for grammars that are served from the cache.
+    pub cache_grammar_artifacts: bool,
+    /// Whether to compile each of a grammar's source files to an object separately, reusing
+    /// objects from earlier builds whose source file, headers and Clang arguments are identical.
+    /// Cached objects are stored in the builder's cache directory.
+    ///
+    /// A grammar's generated parser is usually far larger than its scanner, so this mostly
+    /// speeds up rebuilds of grammars whose scanner is being worked on. Warnings are not
+    /// reported for objects that are served from the cache.
+    pub cache_grammar_objects: bool,
+    /// Whether to also strip the `name` section from `extension.wasm`, leaving only the custom
+    /// sections that Zed needs to load the extension. This reduces the size of the artifact and
+    /// avoids shipping debug names derived from the local build.
+    pub strip_custom_sections: bool,
+    /// Whether to embed a [`BuildInfo`] describing this build into `extension.wasm`, which can
+    /// later be extracted with [`read_build_info`].
+    pub stamp_build_info: bool,
+    /// The timestamp to build the extension at, which is exported to `cargo` and Clang as
+    /// `SOURCE_DATE_EPOCH` and used as the [`BuildInfo::timestamp`], so that timestamps embedded
+    /// into the extension's artifacts don't vary between builds of the same source.
+    ///
+    /// Together with [`Self::strip_custom_sections`], this makes rebuilds of an extension much
+    /// closer to byte-identical. The artifacts can still differ in:
+    /// - the absolute paths of the extension and `cargo` home, which `rustc` embeds in panic
+    ///   messages;
+    /// - the versions of the Rust toolchain, Clang and WASI sysroot used to build them;
+    /// - the [`BuildInfo::builder_version`] and Git state, when build info is stamped.
+    pub source_date_epoch: Option<SourceDateEpoch>,
+    /// Whether to record the Git commit and dirty state of the extension's source in
+    /// [`CompileOutput::source_state`]. This is always recorded when [`Self::stamp_build_info`]
+    /// is set.
+    pub record_source_state: bool,
+    /// Whether to copy the extension into a working directory in the builder's cache directory
+    /// and build it there, leaving the extension directory untouched. This allows building
+    /// extensions whose directory is read-only. The built artifacts are written to
+    /// [`CompileOutput::build_dir`].
+    pub copy_source_to_cache: bool,
+    /// Whether to validate the extension's themes against the theme schema before building.
+    pub validate_themes: bool,
+    /// Whether to check that every icon referenced by the extension's icon themes exists in its
+    /// `icons` directory before building, since only that directory is packaged.
+    pub validate_icon_themes: bool,
+    /// Whether to validate the extension's snippets file before building.
+    pub validate_snippets: bool,
+    /// Whether to validate the `config.toml` of each of the extension's languages before
+    /// building.
+    pub validate_languages: bool,
+    /// Whether to use the manifest as-is, rather than filling in the languages, themes, icon
+    /// themes, snippets, grammars and library kind discovered in the extension directory. This
+    /// is for callers that maintain a complete manifest themselves, and skips the discovery's
+    /// filesystem traversal.
+    pub skip_defaults: bool,
+    /// Additional directories, relative to each grammar's repository, to add to the include path
+    /// when compiling grammars that have an external scanner. The repository root is always
+    /// included for such grammars.
+    pub grammar_include_dirs: Vec<PathBuf>,
+    /// The C language standard to compile grammars with, such as `c11`, passed to Clang as
+    /// `-std`. Grammars can override this with [`GrammarManifestEntry::c_standard`]. When unset,
+    /// Clang's default standard is used.
+    pub grammar_c_standard: Option<String>,
+    /// Whether to compile grammars with link-time optimization in release builds, which allows
+    /// code shared between a grammar's parser and scanner to be optimized together. The
+    /// reduction in size varies between grammars, and is largest for grammars with big
+    /// external scanners; compare the sizes in [`CompileOutput::diff`] to measure it. Has no
+    /// effect unless [`Self::release`] is set.
+    pub grammar_lto: bool,
+    /// Whether to compile grammars with undefined behavior checks, including out-of-bounds array
+    /// accesses, which trap when violated. This helps to track down memory bugs in scanners
+    /// that would otherwise corrupt memory and crash the host later on.
+    ///
+    /// The checks make grammars noticeably larger and slower to parse, so this is only intended
+    /// for debugging.
+    pub sanitize_grammars: bool,
+    /// Whether to also link all of the extension's grammars into a single wasm, written to
+    /// `grammars.wasm` in the extension directory, which exports each grammar's
+    /// `tree_sitter_<name>` function. Every grammar must be compiled from source.
+    pub combine_grammars: bool,
+    /// Whether to check, after building, that every grammar referenced by the extension's
+    /// languages was compiled as part of the build.
+    pub verify_language_grammars: bool,
+    /// Whether to write each of the extension's themes, after validating it, in a canonical
+    /// form into [`CompileOutput::normalized_themes_dir`]: its colors written as `#rrggbbaa`
+    /// and its JSON reformatted. The extension's own theme files are left untouched.
+    pub normalize_themes: bool,
+    /// The minimum Zed extension API version that the extension's Rust library may target.
+    /// Extensions built against an older `zed_extension_api` fail to build.
+    pub min_api_version: Option<SemanticVersion>,
+    /// The version of Zed that the extension is being built for. When set, extensions whose
+    /// manifest declares a newer `min_zed_version` fail to build.
+    pub target_zed_version: Option<SemanticVersion>,
+    /// Whether to check that each grammar's revision exists in its repository before building
+    /// anything, with [`ExtensionBuilder::preflight_grammar_revs`].
+    pub preflight_grammar_revs: bool,
+    /// Whether to run the post-build command declared in the extension's manifest, if any, once
+    /// the extension has been compiled. The command runs arbitrary code from the extension, so
+    /// it is skipped with a warning unless this is set.
+    pub run_post_build_command: bool,
+    /// How long the extension's post-build command may run for before it is killed and the
+    /// build fails. Defaults to five minutes.
+    pub post_build_timeout: Option<Duration>,
+    /// Called as each phase of the build finishes, to report the overall progress of the build.
+    pub on_progress: Option<Arc<dyn Fn(BuildProgressEvent) + Send + Sync>>,
+    /// A writer, such as standard error, to write each [`BuildProgressEvent`] to as a line of
+    /// JSON, alongside any [`Self::on_progress`] callback. This lets a process that runs the
+    /// builder as a subprocess follow the progress of the build.
+    pub progress_json_writer: Option<Arc<Mutex<dyn io::Write + Send>>>,
+}
+
+impl CompileExtensionOptions {
+    fn report_progress(&self, event: BuildProgressEvent) {
+        if let Some(writer) = &self.progress_json_writer {
+            if let Err(error) = write_progress_json_line(&mut *writer.lock(), &event) {
+                log::warn!("failed to write build progress: {error}");
+            }
+        }
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(event);
+        }
+    }
+}
+
+/// The `src/lib.rs` of the Rust library created by [`ExtensionBuilder::scaffold`].
+const SCAFFOLD_LIB_RS: &str = r#"use zed_extension_api as zed;
+
+struct Extension;
+
+impl zed::Extension for Extension {
+    fn new() -> Self {
+        Extension
+    }
+}
+
+zed::register_extension!(Extension);
+"#;
+
+/// The options for [`ExtensionBuilder::scaffold`].
+#[derive(Clone, Debug, Default)]
+pub struct ScaffoldOptions {
+    /// The extension's ID, such as `my-extension`.
+    pub id: String,
+    /// The extension's human-readable name.
+    pub name: String,
+    pub description: Option<String>,
+    pub authors: Vec<String>,
+    /// Whether to create a Rust library for the extension, with a `Cargo.toml` and a minimal
+    /// implementation of the extension API.
+    pub rust_library: bool,
+}
+
+/// Metadata about a build of an extension, embedded into its `extension.wasm` when
+/// [`CompileExtensionOptions::stamp_build_info`] is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// The version of the extension builder that built the extension.
+    pub builder_version: String,
+    /// When the extension was built, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The Git commit of the extension's source, if it is in a Git repository.
+    pub source_commit: Option<String>,
+    /// Whether the extension's source had uncommitted changes when it was built.
+    #[serde(default)]
+    pub source_dirty: bool,
+}
+
+/// A crate that building an extension's Rust library compiles, as returned by
+/// [`ExtensionBuilder::rust_build_plan`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlannedCrate {
+    pub name: String,
+    pub version: String,
+    /// Where the crate comes from, such as a registry or Git repository, or `None` for a local
+    /// crate.
+    pub source: Option<String>,
+    /// Whether the crate has a build script, which is compiled and run for the host.
+    pub has_build_script: bool,
+}
+
+/// The state of the Git working tree that an extension was built from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceState {
+    /// The commit checked out in the working tree.
+    pub commit: String,
+    /// Whether the extension's directory had uncommitted changes.
+    pub dirty: bool,
+}
+
+/// A phase of a profile-guided optimization (PGO) build of an extension's Rust library, for
+/// [`CompileExtensionOptions::pgo`].
+///
+/// Optimizing an extension with PGO takes two builds:
+/// 1. Build the extension with [`PgoPhase::Instrument`], install it as a dev extension, and run
+///    representative workloads in Zed. The instrumented extension writes `.profraw` files into
+///    the profile directory.
+/// 2. Merge them with `llvm-profdata merge -o extension.profdata <profile_dir>`, using the
+///    `llvm-profdata` matching the Rust toolchain's LLVM version, then build the extension with
+///    [`PgoPhase::Optimize`] pointing at the merged profile.
+///
+/// Instrumentation requires a Rust toolchain whose profiler runtime supports the extension's
+/// target.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PgoPhase {
+    /// Build an instrumented extension that writes profiles into `profile_dir` as it runs.
+    Instrument { profile_dir: PathBuf },
+    /// Build an extension optimized with the profile data merged into `profile_path`.
+    Optimize { profile_path: PathBuf },
+}
+
+/// Where the timestamp that an extension is built at comes from, for
+/// [`CompileExtensionOptions::source_date_epoch`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceDateEpoch {
+    /// The commit time of the Git commit checked out in the extension's directory.
+    GitCommit,
+    /// A fixed timestamp, in seconds since the Unix epoch.
+    Timestamp(u64),
+}
+
+/// The result of a successful [`ExtensionBuilder::compile_extension`], which can be serialized
+/// as a build report.
+#[derive(Debug, Default, Serialize)]
+pub struct CompileOutput {
+    /// The working directory that the extension was copied to and built in, when
+    /// [`CompileExtensionOptions::copy_source_to_cache`] is set.
+    pub build_dir: Option<PathBuf>,
+    /// The directory that the extension's artifacts were collected into, at the same paths
+    /// relative to it as they would have within the extension directory, when
+    /// [`ExtensionBuilder::sandbox_builds`] is set.
+    pub artifacts_dir: Option<PathBuf>,
+    /// The directory that the extension's themes were written to in canonical form, at the same
+    /// paths relative to it as within the extension directory, when
+    /// [`CompileExtensionOptions::normalize_themes`] is set.
+    pub normalized_themes_dir: Option<PathBuf>,
+    /// The Git state of the extension's source, when
+    /// [`CompileExtensionOptions::record_source_state`] is set and the extension is in a Git
+    /// repository.
+    pub source_state: Option<SourceState>,
+    /// The rendered warnings that `cargo` emitted while building the extension's Rust library.
+    pub rust_warnings: Vec<String>,
+    /// The SHA-256 hash of the `Cargo.lock` that the extension's Rust crate was built with.
+    pub cargo_lock_hash: Option<String>,
+    /// The `SOURCE_DATE_EPOCH` that the extension was built with, when
+    /// [`CompileExtensionOptions::source_date_epoch`] is set.
+    pub source_date_epoch: Option<u64>,
+    /// The extension API version that the extension's Rust library targets, if it has one.
+    pub extension_api_version: Option<SemanticVersion>,
+    /// The oldest version of Zed that the extension's manifest declares it works with.
+    pub min_zed_version: Option<SemanticVersion>,
+    /// The size of the compiled `extension.wasm` in bytes, if the extension has a Rust library.
+    pub extension_wasm_size: Option<u64>,
+    /// The Tree-sitter ABI version of each grammar compiled from source, keyed by grammar name.
+    pub grammar_abi_versions: BTreeMap<Arc<str>, u32>,
+    /// The size of each compiled grammar's wasm in bytes, keyed by grammar name.
+    pub grammar_wasm_sizes: BTreeMap<Arc<str>, u64>,
+    /// The grammars whose wasm was reused from the grammar artifact cache, rather than compiled.
+    pub cached_grammars: Vec<Arc<str>>,
+    /// The size of the combined grammar wasm in bytes, if grammars were combined.
+    pub combined_grammar_wasm_size: Option<u64>,
+    /// The `tree_sitter_<name>` functions exported by the combined grammar wasm.
+    pub combined_grammar_exports: Vec<String>,
+    /// Warnings about each grammar that compiled successfully, keyed by grammar name.
+    pub grammar_warnings: BTreeMap<Arc<str>, Vec<String>>,
+    /// The diagnostics emitted while compiling, when
+    /// [`CompileExtensionOptions::collect_diagnostics`] is set.
+    pub diagnostics: CompileDiagnostics,
+}
+
+impl CompileOutput {
+    /// Checks whether the built extension can be loaded by a Zed host that supports the extension
+    /// API versions in `host_api_versions`, such as those returned by [`wasm_api_version_range`],
+    /// and the Tree-sitter ABI versions in `host_grammar_abi_versions`, returning a description
+    /// of each incompatibility.
+    pub fn validate_against_host(
+        &self,
+        host_api_versions: &RangeInclusive<SemanticVersion>,
+        host_grammar_abi_versions: &RangeInclusive<u32>,
+    ) -> Vec<String> {
+        let mut incompatibilities = Vec::new();
+        if let Some(extension_api_version) = self.extension_api_version {
+            if !host_api_versions.contains(&extension_api_version) {
+                incompatibilities.push(format!(
+                    "extension targets extension API version {extension_api_version}, \
+                    but the host supports versions {} through {}",
+                    host_api_versions.start(),
+                    host_api_versions.end()
+                ));
+            }
+        }
+        for (grammar_name, abi_version) in &self.grammar_abi_versions {
+            if !host_grammar_abi_versions.contains(abi_version) {
+                incompatibilities.push(format!(
+                    "grammar '{grammar_name}' targets Tree-sitter ABI version {abi_version}, \
+                    but the host supports versions {} through {}",
+                    host_grammar_abi_versions.start(),
+                    host_grammar_abi_versions.end()
+                ));
+            }
+        }
+        incompatibilities
+    }
+
+    /// Compares the artifacts of this build against those of a later build of the same extension.
+    pub fn diff(&self, newer: &CompileOutput) -> BuildDiff {
+        let mut grammar_size_deltas = BTreeMap::default();
+        let mut removed_grammars = Vec::new();
+        for (grammar_name, old_size) in &self.grammar_wasm_sizes {
+            match newer.grammar_wasm_sizes.get(grammar_name) {
+                Some(new_size) => {
+                    grammar_size_deltas
+                        .insert(grammar_name.clone(), *new_size as i64 - *old_size as i64);
+                }
+                None => removed_grammars.push(grammar_name.clone()),
+            }
+        }
+        let added_grammars = newer
+            .grammar_wasm_sizes
+            .keys()
+            .filter(|grammar_name| !self.grammar_wasm_sizes.contains_key(*grammar_name))
+            .cloned()
+            .collect();
+
+        BuildDiff {
+            extension_wasm_size_delta: newer.extension_wasm_size.unwrap_or(0) as i64
+                - self.extension_wasm_size.unwrap_or(0) as i64,
+            grammar_size_deltas,
+            added_grammars,
+            removed_grammars,
+        }
+    }
+}
+
+/// An extension to build with [`ExtensionBuilder::compile_extensions`].
+pub struct BatchExtension {
+    pub extension_dir: PathBuf,
+    pub manifest: ExtensionManifest,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BatchBuildOptions {
+    /// Whether to stop at the first extension that fails to build. Otherwise, the remaining
+    /// extensions are still built, and each failure is recorded in
+    /// [`BatchBuildOutput::failures`].
+    pub fail_fast: bool,
+    /// A file to record the extensions that built successfully in, so that a batch build that is
+    /// run again can resume where it left off. Extensions recorded in it at the same version are
+    /// skipped.
+    pub state_path: Option<PathBuf>,
+}
+
+/// The extensions that a batch build has built successfully, as persisted to
+/// [`BatchBuildOptions::state_path`].
+#[derive(Default, Deserialize, Serialize)]
+struct BatchBuildState {
+    /// The version of each extension that was built, keyed by extension ID.
+    built_extensions: BTreeMap<Arc<str>, Arc<str>>,
+}
+
+/// The result of [`ExtensionBuilder::compile_extensions`].
+#[derive(Debug, Default, Serialize)]
+pub struct BatchBuildOutput {
+    /// The output of each extension's build, keyed by extension ID.
+    pub outputs: BTreeMap<Arc<str>, CompileOutput>,
+    /// The error that each extension failed to build with, keyed by extension ID.
+    pub failures: BTreeMap<Arc<str>, String>,
+    /// The extensions that were skipped because an earlier run of the batch build already built
+    /// them.
+    pub skipped: Vec<Arc<str>>,
+    /// The number of grammars whose wasm was reused from the grammar artifact cache.
+    pub grammar_cache_hits: usize,
+    /// The number of grammars that were compiled from source.
+    pub grammar_cache_misses: usize,
+}
+
+/// The result of [`ExtensionBuilder::validate_extensions`].
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationReport {
+    /// The directories of the extensions that were validated, relative to the root directory.
+    pub extension_dirs: Vec<PathBuf>,
+    /// The problems found in each invalid extension, keyed by the extension's directory relative
+    /// to the root directory.
+    pub errors: BTreeMap<PathBuf, Vec<String>>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// The differences between the artifacts of two builds of the same extension.
+#[derive(Debug, PartialEq)]
+pub struct BuildDiff {
+    /// The change in size of `extension.wasm` in bytes.
+    pub extension_wasm_size_delta: i64,
+    /// The change in size in bytes of each grammar present in both builds.
+    pub grammar_size_deltas: BTreeMap<Arc<str>, i64>,
+    pub added_grammars: Vec<Arc<str>>,
+    pub removed_grammars: Vec<Arc<str>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BuildProgressEvent {
+    pub phase: BuildPhase,
+    /// A rough estimate of how much of the build is complete, between `0.0` and `1.0`.
+    ///
+    /// Each phase counts as one unit of work, so this is not precise, but it only ever
+    /// increases over the course of a build.
+    pub fraction_complete: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BuildPhase {
+    /// The dependencies of the extension's Rust crate were downloaded. This is reported when
+    /// `cargo` emits its first message, since it only starts compiling once they have been.
+    RustDependenciesFetched,
+    /// Cargo finished compiling one of the crates that make up the extension's Rust library,
+    /// which may be the extension's own crate or one of its dependencies.
+    RustCrateCompiled {
+        crate_name: Arc<str>,
+        compiled_crates: usize,
+        /// The number of packages in the extension's `Cargo.lock`. This includes packages for
+        /// other platforms, so fewer crates than this may end up being compiled.
+        total_crates: Option<usize>,
+    },
+    RustExtensionCompiled,
+    GrammarCompiled {
+        grammar_name: Arc<str>,
+    },
+}
+
+/// A diagnostic emitted by `rustc` while building an extension, as reported by
+/// `cargo build --message-format=json`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CargoDiagnostic {
+    pub message: String,
+    pub level: String,
+    #[serde(default)]
+    pub spans: Vec<CargoDiagnosticSpan>,
+    pub rendered: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CargoDiagnosticSpan {
+    pub file_name: String,
+    pub line_start: usize,
+    pub column_start: usize,
+    pub is_primary: bool,
+}
+
+impl CargoDiagnostic {
+    /// Returns whether this is rustc's summary of how many warnings it emitted, rather than a
+    /// warning in its own right.
+    fn is_warning_summary(&self) -> bool {
+        self.message.ends_with("warning emitted") || self.message.ends_with("warnings emitted")
+    }
+}
+
+impl From<&CargoDiagnostic> for CompileDiagnostic {
+    fn from(diagnostic: &CargoDiagnostic) -> Self {
+        let primary_span = diagnostic.spans.iter().find(|span| span.is_primary);
+        Self {
+            level: diagnostic.level.clone(),
+            message: diagnostic.message.clone(),
+            file: primary_span.map(|span| span.file_name.clone()),
+            line: primary_span.map(|span| span.line_start),
+            column: primary_span.map(|span| span.column_start),
+        }
+    }
+}
+
+/// The diagnostics emitted by the compilers used to build an extension.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct CompileDiagnostics {
+    /// The diagnostics emitted by `rustc` while building the extension's Rust library.
+    pub rust: Vec<CompileDiagnostic>,
+    /// The diagnostics emitted by Clang while compiling each grammar, keyed by grammar name.
+    pub grammars: BTreeMap<Arc<str>, Vec<CompileDiagnostic>>,
+}
+
+impl CompileDiagnostics {
+    /// Extracts the diagnostics from the error of a build that failed to compile.
+    pub fn from_error(error: &anyhow::Error) -> Option<Self> {
+        if let Some(error) = error.downcast_ref::<CargoBuildError>() {
+            return Some(Self {
+                rust: error.diagnostics.iter().map(Into::into).collect(),
+                ..Default::default()
+            });
+        }
+        if let Some(error) = error.downcast_ref::<ClangBuildError>() {
+            return Some(Self {
+                grammars: BTreeMap::from_iter([(
+                    error.grammar_name.clone(),
+                    error.diagnostics.clone(),
+                )]),
+                ..Default::default()
+            });
+        }
+        None
+    }
+}
+
+/// A diagnostic emitted by `rustc` or Clang, in a form common to both.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CompileDiagnostic {
+    /// The severity of the diagnostic, such as `warning` or `error`.
+    pub level: String,
+    pub message: String,
+    /// The file that the diagnostic points to, if any.
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+
+/// The error returned when `cargo` fails to build an extension's Rust crate.
+#[derive(Debug)]
+pub struct CargoBuildError {
+    pub exit_code: Option<i32>,
+    /// The error diagnostics reported by `rustc`.
+    pub diagnostics: Vec<CargoDiagnostic>,
+    pub stderr: String,
 }
 
+impl fmt::Display for CargoBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to build extension ")?;
+        for diagnostic in &self.diagnostics {
+            if let Some(rendered) = &diagnostic.rendered {
+                write!(f, "{rendered}")?;
+            }
+        }
+        write!(f, "{}", self.stderr)
+    }
+}
+
+impl std::error::Error for CargoBuildError {}
+
+/// The error returned when Clang fails to compile a grammar.
+#[derive(Debug)]
+pub struct ClangBuildError {
+    pub grammar_name: Arc<str>,
+    /// The diagnostics reported by Clang.
+    pub diagnostics: Vec<CompileDiagnostic>,
+    pub stderr: String,
+}
+
+impl fmt::Display for ClangBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to compile {} parser with clang: {}",
+            self.grammar_name, self.stderr
+        )
+    }
+}
+
+impl std::error::Error for ClangBuildError {}
+
 #[derive(Deserialize)]
 struct CargoToml {
     package: CargoTomlPackage,
@@ -70,6 +1003,32 @@ impl ExtensionBuilder {
         Self {
             cache_dir,
             http: http_client,
+            git_credentials: Vec::new(),
+            git_fetch_depth: Some(1),
+            git_url_rewrites: Vec::new(),
+            allowed_grammar_repositories: Vec::new(),
+            denied_grammar_repositories: Vec::new(),
+            namespace_grammar_checkouts: false,
+            tool_dirs: None,
+            grammar_clang_target: DEFAULT_GRAMMAR_CLANG_TARGET.to_string(),
+            wasip1_adapter_path: None,
+            clang_path: None,
+            grammar_linker: None,
+            wasi_sysroot_path: None,
+            wasi_sysroot_sha256: None,
+            wasi_sdk_sha256: None,
+            download_tree_sitter_cli: false,
+            tree_sitter_cli_sha256: None,
+            max_extension_wasm_size: None,
+            max_grammar_wasm_size: None,
+            max_grammars: DEFAULT_MAX_GRAMMARS,
+            clang_retries: DEFAULT_CLANG_RETRIES,
+            allowed_grammar_imports: DEFAULT_ALLOWED_GRAMMAR_IMPORTS
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            sandbox_builds: false,
+            command_runner: Arc::new(RealCommandRunner),
         }
     }
 
@@ -78,8 +1037,10 @@ impl ExtensionBuilder {
         extension_dir: &Path,
         extension_manifest: &mut ExtensionManifest,
         options: CompileExtensionOptions,
-    ) -> Result<()> {
-        populate_defaults(extension_manifest, extension_dir)?;
+    ) -> Result<CompileOutput> {
+        if !options.skip_defaults {
+            populate_defaults(extension_manifest, extension_dir)?;
+        }
 
         if extension_dir.is_relative() {
             bail!(
@@ -88,14 +1049,159 @@ impl ExtensionBuilder {
             );
         }
 
+        if let Some((min_zed_version, target_zed_version)) = extension_manifest
+            .min_zed_version
+            .zip(options.target_zed_version)
+        {
+            if target_zed_version < min_zed_version {
+                bail!(
+                    "extension {} requires Zed {min_zed_version} or newer, \
+                    but is being built for Zed {target_zed_version}",
+                    extension_manifest.id
+                );
+            }
+        }
+
+        if extension_manifest.grammars.len() > self.max_grammars {
+            bail!(
+                "extension {} declares {} grammars, which exceeds the limit of {}",
+                extension_manifest.id,
+                extension_manifest.grammars.len(),
+                self.max_grammars
+            );
+        }
+
+        if options.validate_themes || options.normalize_themes {
+            validate_themes(extension_dir, extension_manifest)?;
+        }
+
+        if options.validate_icon_themes {
+            validate_icon_themes(extension_dir, extension_manifest)?;
+        }
+
+        if options.validate_snippets {
+            validate_snippets(extension_dir, extension_manifest)?;
+        }
+
+        if options.validate_languages {
+            validate_languages(extension_dir, extension_manifest)?;
+        }
+
         fs::create_dir_all(&self.cache_dir).context("failed to create cache dir")?;
 
-        if extension_manifest.lib.kind == Some(ExtensionLibraryKind::Rust) {
+        let mut output = CompileOutput {
+            min_zed_version: extension_manifest.min_zed_version,
+            ..Default::default()
+        };
+
+        if options.normalize_themes {
+            let normalized_themes_dir = self
+                .cache_dir
+                .join("normalized-themes")
+                .join(extension_cache_dir_name(&extension_manifest.id)?);
+            write_normalized_themes(extension_dir, extension_manifest, &normalized_themes_dir)?;
+            output.normalized_themes_dir = Some(normalized_themes_dir);
+        }
+
+        // Local grammar repositories are always resolved relative to the original extension
+        // directory, since they aren't part of the copy.
+        let source_dir = extension_dir;
+        let build_dir;
+        let (extension_dir, sandbox) = if self.sandbox_builds {
+            if let Some(grammar_output_dir) = &options.grammar_output_dir {
+                if grammar_output_dir.is_absolute()
+                    || grammar_output_dir
+                        .components()
+                        .any(|component| component == Component::ParentDir)
+                {
+                    bail!(
+                        "grammar output directory {} must be within the extension directory \
+                        when building in a sandbox",
+                        grammar_output_dir.display()
+                    );
+                }
+            }
+
+            // Unlike `copy_source_to_cache`, nothing is kept from earlier builds.
+            let sandbox = Sandbox {
+                dir: self
+                    .cache_dir
+                    .join("sandboxes")
+                    .join(extension_cache_dir_name(&extension_manifest.id)?),
+            };
+            remove_dir_if_exists(&sandbox.dir)?;
+            build_dir = sandbox.source_dir();
+            log::debug!(
+                "copying extension {} to sandbox {}",
+                source_dir.display(),
+                build_dir.display()
+            );
+            copy_extension_source(source_dir, &build_dir)
+                .with_context(|| format!("failed to copy extension to {}", build_dir.display()))?;
+            (build_dir.as_path(), Some(sandbox))
+        } else if options.copy_source_to_cache {
+            build_dir = self
+                .cache_dir
+                .join("builds")
+                .join(extension_cache_dir_name(&extension_manifest.id)?);
+            log::debug!(
+                "copying extension {} to {}",
+                source_dir.display(),
+                build_dir.display()
+            );
+            copy_extension_source(source_dir, &build_dir)
+                .with_context(|| format!("failed to copy extension to {}", build_dir.display()))?;
+            output.build_dir = Some(build_dir.clone());
+            (build_dir.as_path(), None)
+        } else {
+            (extension_dir, None)
+        };
+
+        if options.record_source_state || options.stamp_build_info {
+            output.source_state = self.source_state(source_dir);
+        }
+        output.source_date_epoch = match options.source_date_epoch {
+            Some(SourceDateEpoch::GitCommit) => Some(self.git_commit_timestamp(source_dir)?),
+            Some(SourceDateEpoch::Timestamp(timestamp)) => Some(timestamp),
+            None => None,
+        };
+
+        let is_rust_extension = extension_manifest.lib.kind == Some(ExtensionLibraryKind::Rust);
+        let total_units = usize::from(is_rust_extension) + extension_manifest.grammars.len();
+        let mut completed_units = 0;
+        let mut report_progress = |phase| {
+            completed_units += 1;
+            options.report_progress(BuildProgressEvent {
+                phase,
+                fraction_complete: completed_units as f32 / total_units as f32,
+            });
+        };
+
+        if options.preflight_grammar_revs {
+            self.preflight_grammar_revs(extension_manifest)?;
+        }
+
+        if is_rust_extension {
             log::info!("compiling Rust extension {}", extension_dir.display());
-            self.compile_rust_extension(extension_dir, extension_manifest, options)
-                .await
-                .context("failed to compile Rust extension")?;
+            // The Rust extension is the first unit of work, so its progress is a fraction of that
+            // unit alone.
+            let mut report_rust_progress = |phase, fraction_of_rust_build: f32| {
+                options.report_progress(BuildProgressEvent {
+                    phase,
+                    fraction_complete: fraction_of_rust_build / total_units as f32,
+                });
+            };
+            self.compile_rust_extension(
+                extension_dir,
+                extension_manifest,
+                &options,
+                &mut output,
+                &mut report_rust_progress,
+            )
+            .await
+            .context("failed to compile Rust extension")?;
             log::info!("compiled Rust extension {}", extension_dir.display());
+            report_progress(BuildPhase::RustExtensionCompiled);
         }
 
         for (debug_adapter_name, meta) in &mut extension_manifest.debug_adapters {
@@ -114,6 +1220,16 @@ impl ExtensionBuilder {
                 format!("Debug adapter schema for `{debug_adapter_name}` (path: `{debug_adapter_schema_path:?}`) is not a valid JSON")
             })?;
         }
+        if let Some(grammar_output_dir) = &options.grammar_output_dir {
+            fs::create_dir_all(extension_dir.join(grammar_output_dir))
+                .context("failed to create grammar output dir")?;
+        }
+
+        self.download_prebuilt_grammars(extension_dir, extension_manifest, &options, &mut output)
+            .await
+            .context("failed to download prebuilt grammars")?;
+
+        let mut all_grammar_sources = Vec::new();
         for (grammar_name, grammar_metadata) in &extension_manifest.grammars {
             let snake_cased_grammar_name = grammar_name.to_snake_case();
             if grammar_name.as_ref() != snake_cased_grammar_name.as_str() {
@@ -122,334 +1238,446 @@ impl ExtensionBuilder {
                 );
             }
 
-            log::info!(
-                "compiling grammar {grammar_name} for extension {}",
-                extension_dir.display()
-            );
-            self.compile_grammar(extension_dir, grammar_name.as_ref(), grammar_metadata)
-                .await
-                .with_context(|| format!("failed to compile grammar '{grammar_name}'"))?;
-            log::info!(
-                "compiled grammar {grammar_name} for extension {}",
-                extension_dir.display()
-            );
+            let grammar_sources = if grammar_metadata.prebuilt_url.is_some() {
+                None
+            } else {
+                log::info!(
+                    "compiling grammar {grammar_name} for extension {}",
+                    extension_dir.display()
+                );
+                let grammar_sources = self
+                    .compile_grammar(
+                        extension_dir,
+                        source_dir,
+                        grammar_name.as_ref(),
+                        grammar_metadata,
+                        &options,
+                        &mut output,
+                    )
+                    .await
+                    .with_context(|| format!("failed to compile grammar '{grammar_name}'"))?;
+                log::info!(
+                    "compiled grammar {grammar_name} for extension {}",
+                    extension_dir.display()
+                );
+                grammar_sources
+            };
+            all_grammar_sources.push((grammar_name.clone(), grammar_sources));
+            report_progress(BuildPhase::GrammarCompiled {
+                grammar_name: grammar_name.clone(),
+            });
         }
 
-        log::info!("finished compiling extension {}", extension_dir.display());
-        Ok(())
-    }
-
-    async fn compile_rust_extension(
-        &self,
-        extension_dir: &Path,
-        manifest: &mut ExtensionManifest,
-        options: CompileExtensionOptions,
-    ) -> anyhow::Result<()> {
-        self.install_rust_wasm_target_if_needed()?;
-
-        let cargo_toml_content = fs::read_to_string(extension_dir.join("Cargo.toml"))?;
-        let cargo_toml: CargoToml = toml::from_str(&cargo_toml_content)?;
+        if options.combine_grammars && !all_grammar_sources.is_empty() {
+            self.compile_combined_grammar_wasm(
+                extension_dir,
+                &all_grammar_sources,
+                &options,
+                &mut output,
+            )
+            .await
+            .context("failed to compile combined grammar wasm")?;
+        }
 
-        log::info!(
-            "compiling Rust crate for extension {}",
-            extension_dir.display()
-        );
-        let output = util::command::new_std_command("cargo")
-            .args(["build", "--target", RUST_TARGET])
-            .args(options.release.then_some("--release"))
-            .arg("--target-dir")
-            .arg(extension_dir.join("target"))
-            // WASI builds do not work with sccache and just stuck, so disable it.
-            .env("RUSTC_WRAPPER", "")
-            .current_dir(extension_dir)
-            .output()
-            .context("failed to run `cargo`")?;
-        if !output.status.success() {
-            bail!(
-                "failed to build extension {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+        if options.verify_language_grammars {
+            verify_language_grammars(extension_dir, extension_manifest, &output)?;
         }
 
-        log::info!(
-            "compiled Rust crate for extension {}",
-            extension_dir.display()
-        );
+        if let Some(post_build) = &extension_manifest.post_build {
+            if options.run_post_build_command {
+                self.run_post_build_command(
+                    extension_dir,
+                    post_build,
+                    options
+                        .post_build_timeout
+                        .unwrap_or(DEFAULT_POST_BUILD_TIMEOUT),
+                )?;
+            } else {
+                log::warn!(
+                    "skipping post-build command `{}` of extension {}, since running it was not enabled",
+                    post_build.command,
+                    extension_dir.display()
+                );
+            }
+        }
 
-        let mut wasm_path = PathBuf::from(extension_dir);
-        wasm_path.extend([
-            "target",
-            RUST_TARGET,
-            if options.release { "release" } else { "debug" },
-            &cargo_toml
-                .package
-                .name
-                // The wasm32-wasip2 target normalizes `-` in package names to `_` in the resulting `.wasm` file.
-                .replace('-', "_"),
-        ]);
-        wasm_path.set_extension("wasm");
+        if let Some(sandbox) = &sandbox {
+            let artifacts_dir = sandbox.dir.join("artifacts");
+            collect_sandbox_artifacts(extension_dir, &artifacts_dir, extension_manifest, &options)
+                .context("failed to collect artifacts from sandbox")?;
+            output.artifacts_dir = Some(artifacts_dir);
+            // Only the artifacts are kept, so that a successful build doesn't leave a copy of
+            // the extension's source behind. Failed builds rely on the sandbox's `Drop`.
+            remove_dir_if_exists(&sandbox.source_dir())?;
+        }
 
-        log::info!(
-            "encoding wasm component for extension {}",
-            extension_dir.display()
-        );
+        log::info!("finished compiling extension {}", extension_dir.display());
+        Ok(output)
+    }
 
-        let component_bytes = fs::read(&wasm_path)
-            .with_context(|| format!("failed to read output module `{}`", wasm_path.display()))?;
+    /// Builds several extensions, such as all of those in a monorepo, with the same options.
+    ///
+    /// Grammar artifacts are always cached, so a grammar that several extensions share, from the
+    /// same repository and revision, is only compiled once.
+    pub async fn compile_extensions(
+        &self,
+        extensions: &mut [BatchExtension],
+        options: CompileExtensionOptions,
+        batch_options: BatchBuildOptions,
+    ) -> Result<BatchBuildOutput> {
+        let mut state = match &batch_options.state_path {
+            Some(state_path) if state_path.exists() => {
+                let state = fs::read(state_path).with_context(|| {
+                    format!("failed to read batch build state {}", state_path.display())
+                })?;
+                serde_json::from_slice(&state).with_context(|| {
+                    format!("invalid batch build state {}", state_path.display())
+                })?
+            }
+            _ => BatchBuildState::default(),
+        };
 
-        let component_bytes = self
-            .strip_custom_sections(&component_bytes)
-            .context("failed to strip debug sections from wasm component")?;
+        let mut batch_output = BatchBuildOutput::default();
+        for extension in extensions {
+            let extension_id = extension.manifest.id.clone();
+            if state.built_extensions.get(&extension_id) == Some(&extension.manifest.version) {
+                log::info!("skipping extension {extension_id}, which was already built");
+                batch_output.skipped.push(extension_id);
+                continue;
+            }
 
-        let wasm_extension_api_version =
-            parse_wasm_extension_version(&manifest.id, &component_bytes)
-                .context("compiled wasm did not contain a valid zed extension api version")?;
-        manifest.lib.version = Some(wasm_extension_api_version);
+            let options = CompileExtensionOptions {
+                cache_grammar_artifacts: true,
+                ..options.clone()
+            };
+            let output = match self
+                .compile_extension(&extension.extension_dir, &mut extension.manifest, options)
+                .await
+            {
+                Ok(output) => output,
+                Err(error) if !batch_options.fail_fast => {
+                    log::error!("failed to compile extension {extension_id}: {error:#}");
+                    batch_output
+                        .failures
+                        .insert(extension_id, format!("{error:#}"));
+                    continue;
+                }
+                Err(error) => {
+                    return Err(
+                        error.context(format!("failed to compile extension {extension_id}"))
+                    );
+                }
+            };
+
+            if let Some(state_path) = &batch_options.state_path {
+                state
+                    .built_extensions
+                    .insert(extension_id.clone(), extension.manifest.version.clone());
+                fs::write(state_path, serde_json::to_vec_pretty(&state)?).with_context(|| {
+                    format!("failed to write batch build state {}", state_path.display())
+                })?;
+            }
 
-        let extension_file = extension_dir.join("extension.wasm");
-        fs::write(extension_file.clone(), &component_bytes)
-            .context("failed to write extension.wasm")?;
+            let compiled_grammars = extension
+                .manifest
+                .grammars
+                .values()
+                .filter(|grammar| grammar.prebuilt_url.is_none())
+                .count();
+            batch_output.grammar_cache_hits += output.cached_grammars.len();
+            batch_output.grammar_cache_misses +=
+                compiled_grammars.saturating_sub(output.cached_grammars.len());
+            batch_output.outputs.insert(extension_id, output);
+        }
 
         log::info!(
-            "extension {} written to {}",
-            extension_dir.display(),
-            extension_file.display()
+            "compiled {} extensions, with {} failures and {} skipped, reusing {} of {} grammars \
+            from the cache",
+            batch_output.outputs.len(),
+            batch_output.failures.len(),
+            batch_output.skipped.len(),
+            batch_output.grammar_cache_hits,
+            batch_output.grammar_cache_hits + batch_output.grammar_cache_misses
         );
+        Ok(batch_output)
+    }
 
-        Ok(())
+    fn command(&self, program: impl AsRef<OsStr>) -> Result<Command> {
+        let mut command = util::command::new_std_command(program);
+        if self.sandbox_builds {
+            command.env_clear();
+            for name in SANDBOX_ENV_VARS {
+                if let Some(value) = env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        if let Some(tool_dirs) = &self.tool_dirs {
+            // A `PATH` set on the command is also used to resolve the program itself.
+            command.env(
+                "PATH",
+                env::join_paths(tool_dirs).context("invalid tool directory")?,
+            );
+        }
+        Ok(command)
     }
 
-    async fn compile_grammar(
+    /// Runs the post-build command declared in an extension's manifest in the extension
+    /// directory, failing if it exits unsuccessfully or runs for longer than `timeout`.
+    fn run_post_build_command(
         &self,
         extension_dir: &Path,
-        grammar_name: &str,
-        grammar_metadata: &GrammarManifestEntry,
+        post_build: &PostBuildManifestEntry,
+        timeout: Duration,
     ) -> Result<()> {
-        let clang_path = self.install_wasi_sdk_if_needed().await?;
-
-        let mut grammar_repo_dir = extension_dir.to_path_buf();
-        grammar_repo_dir.extend(["grammars", grammar_name]);
-
-        let mut grammar_wasm_path = grammar_repo_dir.clone();
-        grammar_wasm_path.set_extension("wasm");
-
-        log::info!("checking out {grammar_name} parser");
-        self.checkout_repo(
-            &grammar_repo_dir,
-            &grammar_metadata.repository,
-            &grammar_metadata.rev,
-        )?;
-
-        let base_grammar_path = grammar_metadata
-            .path
-            .as_ref()
-            .map(|path| grammar_repo_dir.join(path))
-            .unwrap_or(grammar_repo_dir);
-
-        let src_path = base_grammar_path.join("src");
-        let parser_path = src_path.join("parser.c");
-        let scanner_path = src_path.join("scanner.c");
-
-        log::info!("compiling {grammar_name} parser");
-        let clang_output = util::command::new_std_command(&clang_path)
-            .args(["-fPIC", "-shared", "-Os"])
-            .arg(format!("-Wl,--export=tree_sitter_{grammar_name}"))
-            .arg("-o")
-            .arg(&grammar_wasm_path)
-            .arg("-I")
-            .arg(&src_path)
-            .arg(&parser_path)
-            .args(scanner_path.exists().then_some(scanner_path))
-            .output()
-            .context("failed to run clang")?;
-
-        if !clang_output.status.success() {
+        log::info!(
+            "running post-build command `{}` for extension {}",
+            post_build.command,
+            extension_dir.display()
+        );
+        let output = self
+            .command_runner
+            .output_with_timeout(
+                self.command(&post_build.command)?
+                    .args(&post_build.args)
+                    .current_dir(extension_dir),
+                timeout,
+            )
+            .with_context(|| {
+                format!("failed to run post-build command `{}`", post_build.command)
+            })?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
             bail!(
-                "failed to compile {} parser with clang: {}",
-                grammar_name,
-                String::from_utf8_lossy(&clang_output.stderr),
+                "post-build command `{}` failed with {}:\n{stdout}{stderr}",
+                post_build.command,
+                output.status
             );
         }
-
+        for line in stdout.lines().chain(stderr.lines()) {
+            log::info!("post-build: {line}");
+        }
         Ok(())
     }
 
-    fn checkout_repo(&self, directory: &Path, url: &str, rev: &str) -> Result<()> {
-        let git_dir = directory.join(".git");
-
-        if directory.exists() {
-            let remotes_output = util::command::new_std_command("git")
-                .arg("--git-dir")
-                .arg(&git_dir)
-                .args(["remote", "-v"])
-                .output()?;
-            let has_remote = remotes_output.status.success()
-                && String::from_utf8_lossy(&remotes_output.stdout)
-                    .lines()
-                    .any(|line| {
-                        let mut parts = line.split(|c: char| c.is_whitespace());
-                        parts.next() == Some("origin") && parts.any(|part| part == url)
-                    });
-            if !has_remote {
-                bail!(
-                    "grammar directory '{}' already exists, but is not a git clone of '{}'",
-                    directory.display(),
-                    url
-                );
-            }
-        } else {
-            fs::create_dir_all(directory).with_context(|| {
-                format!("failed to create grammar directory {}", directory.display(),)
-            })?;
-            let init_output = util::command::new_std_command("git")
-                .arg("init")
-                .current_dir(directory)
-                .output()?;
-            if !init_output.status.success() {
-                bail!(
-                    "failed to run `git init` in directory '{}'",
-                    directory.display()
-                );
-            }
-
-            let remote_add_output = util::command::new_std_command("git")
-                .arg("--git-dir")
-                .arg(&git_dir)
-                .args(["remote", "add", "origin", url])
-                .output()
-                .context("failed to execute `git remote add`")?;
-            if !remote_add_output.status.success() {
-                bail!(
-                    "failed to add remote {url} for git repository {}",
-                    git_dir.display()
-                );
-            }
+    /// Returns the extension directories, out of `extension_dirs`, that contain files which
+    /// differ between `base_ref` and the working tree of the Git repository at `repository_dir`.
+    ///
+    /// Relative extension directories are resolved against `repository_dir`.
+    pub fn changed_extensions(
+        &self,
+        repository_dir: &Path,
+        base_ref: &str,
+        extension_dirs: &[PathBuf],
+    ) -> Result<Vec<PathBuf>> {
+        let output = self
+            .command_runner
+            .output(
+                self.command("git")?
+                    .args(["rev-parse", "--show-toplevel"])
+                    .current_dir(repository_dir),
+            )
+            .context("failed to run `git rev-parse`")?;
+        if !output.status.success() {
+            bail!(
+                "{} is not in a git repository: {}",
+                repository_dir.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
         }
-
-        let fetch_output = util::command::new_std_command("git")
-            .arg("--git-dir")
-            .arg(&git_dir)
-            .args(["fetch", "--depth", "1", "origin", rev])
-            .output()
-            .context("failed to execute `git fetch`")?;
-
-        let checkout_output = util::command::new_std_command("git")
-            .arg("--git-dir")
-            .arg(&git_dir)
-            .args(["checkout", rev])
-            .current_dir(directory)
-            .output()
-            .context("failed to execute `git checkout`")?;
-        if !checkout_output.status.success() {
-            if !fetch_output.status.success() {
-                bail!(
-                    "failed to fetch revision {} in directory '{}'",
-                    rev,
-                    directory.display()
-                );
-            }
+        // Canonicalize both paths so that the extension directories can be compared with the
+        // changed paths, even when the repository is reached through a symlink.
+        let repository_root = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim())
+            .canonicalize()
+            .context("failed to canonicalize repository root")?;
+        let repository_dir = repository_dir
+            .canonicalize()
+            .with_context(|| format!("failed to canonicalize {}", repository_dir.display()))?;
+
+        let output = self
+            .command_runner
+            .output(
+                self.command("git")?
+                    .args(["diff", "--name-only", base_ref, "--"])
+                    .current_dir(&repository_dir),
+            )
+            .context("failed to run `git diff`")?;
+        if !output.status.success() {
             bail!(
-                "failed to checkout revision {} in directory '{}': {}",
-                rev,
-                directory.display(),
-                String::from_utf8_lossy(&checkout_output.stderr)
+                "failed to diff against {base_ref}: {}",
+                String::from_utf8_lossy(&output.stderr)
             );
         }
-
-        Ok(())
+        let changed_paths = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| repository_root.join(line))
+            .collect::<Vec<_>>();
+
+        let mut changed_extension_dirs = Vec::new();
+        for extension_dir in extension_dirs {
+            let absolute_extension_dir = repository_dir.join(extension_dir);
+            let absolute_extension_dir = absolute_extension_dir
+                .canonicalize()
+                .unwrap_or(absolute_extension_dir);
+            if changed_paths
+                .iter()
+                .any(|path| path.starts_with(&absolute_extension_dir))
+            {
+                changed_extension_dirs.push(extension_dir.clone());
+            }
+        }
+        Ok(changed_extension_dirs)
     }
 
-    fn install_rust_wasm_target_if_needed(&self) -> Result<()> {
-        let rustc_output = util::command::new_std_command("rustc")
-            .arg("--print")
-            .arg("sysroot")
-            .output()
-            .context("failed to run rustc")?;
-        if !rustc_output.status.success() {
-            bail!(
-                "failed to retrieve rust sysroot: {}",
-                String::from_utf8_lossy(&rustc_output.stderr)
-            );
+    /// Creates a minimal extension in `target_dir` that [`Self::compile_extension`] accepts: an
+    /// `extension.toml`, the conventional `languages` and `themes` directories, and, if
+    /// requested, a Rust library that implements the extension API.
+    pub fn scaffold(target_dir: &Path, options: ScaffoldOptions) -> Result<()> {
+        let manifest_path = target_dir.join("extension.toml");
+        if manifest_path.exists() {
+            bail!("{} already exists", manifest_path.display());
         }
 
-        let sysroot = PathBuf::from(String::from_utf8(rustc_output.stdout)?.trim());
-        if sysroot.join("lib/rustlib").join(RUST_TARGET).exists() {
-            return Ok(());
+        for dir_name in ["languages", "themes"] {
+            fs::create_dir_all(target_dir.join(dir_name))
+                .with_context(|| format!("failed to create {dir_name} directory"))?;
         }
 
-        let output = util::command::new_std_command("rustup")
-            .args(["target", "add", RUST_TARGET])
-            .stderr(Stdio::piped())
-            .stdout(Stdio::inherit())
-            .output()
-            .context("failed to run `rustup target add`")?;
-        if !output.status.success() {
-            bail!(
-                "failed to install the `{RUST_TARGET}` target: {}",
-                String::from_utf8_lossy(&rustc_output.stderr)
+        let mut manifest = toml::Table::new();
+        manifest.insert("id".into(), options.id.clone().into());
+        manifest.insert("name".into(), options.name.into());
+        manifest.insert("version".into(), "0.0.1".into());
+        manifest.insert("schema_version".into(), 1.into());
+        if let Some(description) = options.description {
+            manifest.insert("description".into(), description.into());
+        }
+        manifest.insert("authors".into(), options.authors.into());
+        fs::write(&manifest_path, toml::to_string_pretty(&manifest)?)
+            .with_context(|| format!("failed to write {}", manifest_path.display()))?;
+
+        if options.rust_library {
+            let extension_api_version = *wasm_api_version_range().end();
+            let mut package = toml::Table::new();
+            package.insert("name".into(), options.id.into());
+            package.insert("version".into(), "0.0.1".into());
+            package.insert("edition".into(), "2021".into());
+            package.insert("publish".into(), false.into());
+            let mut lib = toml::Table::new();
+            lib.insert("crate-type".into(), vec!["cdylib"].into());
+            let mut dependencies = toml::Table::new();
+            dependencies.insert(
+                "zed_extension_api".into(),
+                extension_api_version.to_string().into(),
             );
+            let mut cargo_toml = toml::Table::new();
+            cargo_toml.insert("package".into(), package.into());
+            cargo_toml.insert("lib".into(), lib.into());
+            cargo_toml.insert("dependencies".into(), dependencies.into());
+            fs::write(
+                target_dir.join("Cargo.toml"),
+                toml::to_string_pretty(&cargo_toml)?,
+            )
+            .context("failed to write Cargo.toml")?;
+
+            let src_dir = target_dir.join("src");
+            fs::create_dir_all(&src_dir).context("failed to create src directory")?;
+            fs::write(src_dir.join("lib.rs"), SCAFFOLD_LIB_RS)
+                .context("failed to write src/lib.rs")?;
         }
 
+        log::info!("created extension scaffold in {}", target_dir.display());
         Ok(())
     }
 
-    async fn install_wasi_sdk_if_needed(&self) -> Result<PathBuf> {
-        let url = if let Some(asset_name) = WASI_SDK_ASSET_NAME {
-            format!("{WASI_SDK_URL}{asset_name}")
-        } else {
-            bail!("wasi-sdk is not available for platform {}", env::consts::OS);
-        };
-
-        let wasi_sdk_dir = self.cache_dir.join("wasi-sdk");
-        let mut clang_path = wasi_sdk_dir.clone();
-        clang_path.extend(["bin", &format!("clang{}", env::consts::EXE_SUFFIX)]);
+    /// Stamps a prebuilt `extension.wasm` with build info, as
+    /// [`CompileExtensionOptions::stamp_build_info`] does during a build, without rebuilding it.
+    /// This lets an extension be built and published on different machines. Build info stamped
+    /// earlier is superseded.
+    ///
+    /// The wasm is validated before and after it is stamped. Returns the SHA-256 hash of the
+    /// stamped wasm, so that it can be published alongside it.
+    pub fn stamp_prebuilt_extension(
+        &self,
+        wasm_path: &Path,
+        manifest: &ExtensionManifest,
+        source_state: Option<&SourceState>,
+    ) -> Result<String> {
+        let mut wasm_bytes = fs::read(wasm_path)
+            .with_context(|| format!("failed to read {}", wasm_path.display()))?;
+        validate_prebuilt_extension(&wasm_bytes, manifest)
+            .with_context(|| format!("{} is not a valid extension", wasm_path.display()))?;
+
+        append_build_info(&mut wasm_bytes, source_state, None)?;
+        validate_prebuilt_extension(&wasm_bytes, manifest)
+            .context("extension is invalid after stamping it")?;
+
+        fs::write(wasm_path, &wasm_bytes)
+            .with_context(|| format!("failed to write {}", wasm_path.display()))?;
+        Ok(format!("{:x}", Sha256::digest(&wasm_bytes)))
+    }
 
-        if fs::metadata(&clang_path).map_or(false, |metadata| metadata.is_file()) {
-            return Ok(clang_path);
+    /// Migrates a legacy extension on the v0 schema to the v1 schema, by writing an
+    /// `extension.toml` populated from its `extension.json`, `grammars/*.toml` files and
+    /// discovered assets, and removing the `extension.json` that it supersedes.
+    pub fn migrate_v0_to_v1(&self, extension_dir: &Path) -> Result<ExtensionManifest> {
+        let extension_id = extension_dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .context("invalid extension name")?;
+
+        let old_manifest_path = extension_dir.join("extension.json");
+        if !old_manifest_path.exists() {
+            bail!("extension {extension_id} has no extension.json, so it is not on the v0 schema");
+        }
+        let manifest_path = extension_dir.join("extension.toml");
+        if manifest_path.exists() {
+            bail!("extension {extension_id} already has an extension.toml");
         }
 
-        let mut tar_out_dir = wasi_sdk_dir.clone();
-        tar_out_dir.set_extension("archive");
-
-        fs::remove_dir_all(&wasi_sdk_dir).ok();
-        fs::remove_dir_all(&tar_out_dir).ok();
-
-        log::info!("downloading wasi-sdk to {}", wasi_sdk_dir.display());
-        let mut response = self.http.get(&url, AsyncBody::default(), true).await?;
-        let body = BufReader::new(response.body_mut());
-        let body = GzipDecoder::new(body);
-        let tar = Archive::new(body);
-
-        tar.unpack(&tar_out_dir)
-            .await
-            .context("failed to unpack wasi-sdk archive")?;
+        let old_manifest_content = fs::read_to_string(&old_manifest_path)
+            .with_context(|| format!("failed to read {}", old_manifest_path.display()))?;
+        let old_manifest: OldExtensionManifest = serde_json::from_str(&old_manifest_content)
+            .with_context(|| format!("invalid extension.json for extension {extension_id}"))?;
+
+        let mut manifest = manifest_from_old_manifest(old_manifest, extension_id);
+        populate_defaults(&mut manifest, extension_dir)?;
+        manifest.schema_version = SchemaVersion(1);
+        if let Some(snippets_path) = &manifest.snippets {
+            if let Ok(relative_snippets_path) = snippets_path.strip_prefix(extension_dir) {
+                manifest.snippets = Some(relative_snippets_path.to_path_buf());
+            }
+        }
 
-        let inner_dir = fs::read_dir(&tar_out_dir)?
-            .next()
-            .context("no content")?
-            .context("failed to read contents of extracted wasi archive directory")?
-            .path();
-        fs::rename(&inner_dir, &wasi_sdk_dir).context("failed to move extracted wasi dir")?;
-        fs::remove_dir_all(&tar_out_dir).ok();
+        let manifest_content =
+            toml::to_string_pretty(&manifest).context("failed to serialize extension.toml")?;
+        fs::write(&manifest_path, manifest_content)
+            .with_context(|| format!("failed to write {}", manifest_path.display()))?;
+        fs::remove_file(&old_manifest_path)
+            .with_context(|| format!("failed to remove {}", old_manifest_path.display()))?;
 
-        Ok(clang_path)
+        log::info!("migrated extension {extension_id} to extension.toml");
+        Ok(manifest)
     }
 
     // This was adapted from:
     // https://github.com/bytecodealliance/wasm-tools/blob/e8809bb17fcf69aa8c85cd5e6db7cff5cf36b1de/src/bin/wasm-tools/strip.rs
-    fn strip_custom_sections(&self, input: &Vec<u8>) -> Result<Vec<u8>> {
+    fn strip_custom_sections(&self, input: &Vec<u8>, keep_name_section: bool) -> Result<Vec<u8>> {
         use wasmparser::Payload::*;
 
         let strip_custom_section = |name: &str| {
             // Default strip everything but:
-            // * the `name` section
+            // * the `name` section, unless requested
             // * any `component-type` sections
             // * the `dylink.0` section
-            // * our custom version section
-            name != "name"
+            // * our custom version, build info, and manifest sections
+            (name != "name" || !keep_name_section)
                 && !name.starts_with("component-type:")
                 && name != "dylink.0"
                 && name != "zed:api-version"
+                && name != BUILD_INFO_SECTION_NAME
+                && name != MANIFEST_SECTION_NAME
         };
 
         let mut output = Vec::new();
@@ -518,16 +1746,92 @@ fn populate_defaults(manifest: &mut ExtensionManifest, extension_path: &Path) ->
         manifest.themes.clear();
     }
 
-    let cargo_toml_path = extension_path.join("Cargo.toml");
-    if cargo_toml_path.exists() {
-        manifest.lib.kind = Some(ExtensionLibraryKind::Rust);
+    if let Some(library_kind) = detect_library_kind(extension_path) {
+        manifest.lib.kind = Some(library_kind);
     }
 
+    discover_languages(manifest, extension_path)?;
+    discover_themes(manifest, extension_path)?;
+    discover_icon_themes(manifest, extension_path)?;
+    discover_snippets(manifest, extension_path)?;
+    discover_v0_grammars(manifest, extension_path)?;
+
+    Ok(())
+}
+
+/// The languages, themes and icon themes of an extension, split by whether its manifest declares
+/// them or they are only picked up by discovery when the extension is built.
+#[derive(Debug, Default, PartialEq)]
+pub struct AssetOrigins {
+    pub declared: ExtensionAssets,
+    pub discovered: ExtensionAssets,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ExtensionAssets {
+    pub languages: Vec<PathBuf>,
+    pub themes: Vec<PathBuf>,
+    pub icon_themes: Vec<PathBuf>,
+}
+
+/// Returns which of the extension's languages, themes and icon themes are declared in `manifest`,
+/// and which would be added to it by discovery. `manifest` should be as read from the extension,
+/// before it is passed to [`ExtensionBuilder::compile_extension`]. The discovered assets of legacy
+/// extensions on the v0 schema replace the declared ones, so all of their assets count as
+/// discovered.
+pub fn asset_origins(manifest: &ExtensionManifest, extension_path: &Path) -> Result<AssetOrigins> {
+    let declared = if manifest.schema_version.is_v0() {
+        ExtensionAssets::default()
+    } else {
+        ExtensionAssets {
+            languages: manifest.languages.clone(),
+            themes: manifest.themes.clone(),
+            icon_themes: manifest.icon_themes.clone(),
+        }
+    };
+
+    let mut populated_manifest = ExtensionManifest {
+        languages: declared.languages.clone(),
+        themes: declared.themes.clone(),
+        icon_themes: declared.icon_themes.clone(),
+        ..manifest.clone()
+    };
+    discover_languages(&mut populated_manifest, extension_path)?;
+    discover_themes(&mut populated_manifest, extension_path)?;
+    discover_icon_themes(&mut populated_manifest, extension_path)?;
+
+    let discovered = ExtensionAssets {
+        languages: populated_manifest
+            .languages
+            .split_off(declared.languages.len()),
+        themes: populated_manifest.themes.split_off(declared.themes.len()),
+        icon_themes: populated_manifest
+            .icon_themes
+            .split_off(declared.icon_themes.len()),
+    };
+    Ok(AssetOrigins {
+        declared,
+        discovered,
+    })
+}
+
+/// Determines the kind of library that the extension has from its files, or `None` if it doesn't
+/// have one.
+pub fn detect_library_kind(extension_dir: &Path) -> Option<ExtensionLibraryKind> {
+    if extension_dir.join("Cargo.toml").exists() {
+        return Some(ExtensionLibraryKind::Rust);
+    }
+    None
+}
+
+/// Adds each directory in the extension's `languages` directory that contains a `config.toml`
+/// to the manifest's languages.
+pub fn discover_languages(manifest: &mut ExtensionManifest, extension_path: &Path) -> Result<()> {
     let languages_dir = extension_path.join("languages");
     if languages_dir.exists() {
-        for entry in fs::read_dir(&languages_dir).context("failed to list languages dir")? {
-            let entry = entry?;
-            let language_dir = entry.path();
+        for language_dir in
+            sorted_dir_entries(&languages_dir).context("failed to list languages dir")?
+        {
             let config_path = language_dir.join("config.toml");
             if config_path.exists() {
                 let relative_language_dir =
@@ -538,12 +1842,14 @@ fn populate_defaults(manifest: &mut ExtensionManifest, extension_path: &Path) ->
             }
         }
     }
+    Ok(())
+}
 
+/// Adds each `.json` file in the extension's `themes` directory to the manifest's themes.
+pub fn discover_themes(manifest: &mut ExtensionManifest, extension_path: &Path) -> Result<()> {
     let themes_dir = extension_path.join("themes");
     if themes_dir.exists() {
-        for entry in fs::read_dir(&themes_dir).context("failed to list themes dir")? {
-            let entry = entry?;
-            let theme_path = entry.path();
+        for theme_path in sorted_dir_entries(&themes_dir).context("failed to list themes dir")? {
             if theme_path.extension() == Some("json".as_ref()) {
                 let relative_theme_path = theme_path.strip_prefix(extension_path)?.to_path_buf();
                 if !manifest.themes.contains(&relative_theme_path) {
@@ -552,12 +1858,17 @@ fn populate_defaults(manifest: &mut ExtensionManifest, extension_path: &Path) ->
             }
         }
     }
+    Ok(())
+}
 
+/// Adds each `.json` file in the extension's `icon_themes` directory to the manifest's icon
+/// themes.
+pub fn discover_icon_themes(manifest: &mut ExtensionManifest, extension_path: &Path) -> Result<()> {
     let icon_themes_dir = extension_path.join("icon_themes");
     if icon_themes_dir.exists() {
-        for entry in fs::read_dir(&icon_themes_dir).context("failed to list icon themes dir")? {
-            let entry = entry?;
-            let icon_theme_path = entry.path();
+        for icon_theme_path in
+            sorted_dir_entries(&icon_themes_dir).context("failed to list icon themes dir")?
+        {
             if icon_theme_path.extension() == Some("json".as_ref()) {
                 let relative_icon_theme_path =
                     icon_theme_path.strip_prefix(extension_path)?.to_path_buf();
@@ -567,50 +1878,1897 @@ fn populate_defaults(manifest: &mut ExtensionManifest, extension_path: &Path) ->
             }
         }
     }
+    Ok(())
+}
+
+/// Returns the paths of the entries in `dir`, sorted so that discovered assets are added to the
+/// manifest in the same order on every platform.
+fn sorted_dir_entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
 
+/// Sets the manifest's snippets to the extension's `snippets.json`, if it has one.
+pub fn discover_snippets(manifest: &mut ExtensionManifest, extension_path: &Path) -> Result<()> {
     let snippets_json_path = extension_path.join("snippets.json");
     if snippets_json_path.exists() {
         manifest.snippets = Some(snippets_json_path);
     }
+    Ok(())
+}
 
-    // For legacy extensions on the v0 schema (aka, using `extension.json`), we want to populate the grammars in
-    // the manifest using the contents of the `grammars` directory.
+/// For legacy extensions on the v0 schema (aka, using `extension.json`), populates the grammars
+/// in the manifest using the contents of the `grammars` directory.
+pub fn discover_v0_grammars(manifest: &mut ExtensionManifest, extension_path: &Path) -> Result<()> {
     if manifest.schema_version.is_v0() {
-        let grammars_dir = extension_path.join("grammars");
-        if grammars_dir.exists() {
-            for entry in fs::read_dir(&grammars_dir).context("failed to list grammars dir")? {
-                let entry = entry?;
-                let grammar_path = entry.path();
-                if grammar_path.extension() == Some("toml".as_ref()) {
-                    #[derive(Deserialize)]
-                    struct GrammarConfigToml {
-                        pub repository: String,
-                        pub commit: String,
-                        #[serde(default)]
-                        pub path: Option<String>,
-                    }
+        for (grammar_name, grammar) in read_v0_grammars(extension_path)? {
+            if !manifest.grammars.contains_key(grammar_name.as_str()) {
+                manifest.grammars.insert(grammar_name.into(), grammar);
+            }
+        }
+    }
+    Ok(())
+}
 
-                    let grammar_config = fs::read_to_string(&grammar_path)?;
-                    let grammar_config: GrammarConfigToml = toml::from_str(&grammar_config)?;
-
-                    let grammar_name = grammar_path
-                        .file_stem()
-                        .and_then(|stem| stem.to_str())
-                        .context("no grammar name")?;
-                    if !manifest.grammars.contains_key(grammar_name) {
-                        manifest.grammars.insert(
-                            grammar_name.into(),
-                            GrammarManifestEntry {
-                                repository: grammar_config.repository,
-                                rev: grammar_config.commit,
-                                path: grammar_config.path,
-                            },
-                        );
-                    }
+/// Returns the paths of the artifacts that compiling the extension would write: its
+/// `extension.wasm` if it has a Rust library, followed by the wasm of each of its grammars and
+/// the combined grammar wasm, if grammars are combined.
+///
+/// The manifest is expected to have had its defaults populated, as they are during compilation.
+pub fn artifact_paths(
+    extension_dir: &Path,
+    manifest: &ExtensionManifest,
+    options: &CompileExtensionOptions,
+) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if manifest.lib.kind == Some(ExtensionLibraryKind::Rust) {
+        paths.push(extension_wasm_path(extension_dir, options));
+    }
+    paths.extend(
+        manifest
+            .grammars
+            .keys()
+            .map(|grammar_name| grammar_wasm_path(extension_dir, grammar_name, options)),
+    );
+    if options.combine_grammars && !manifest.grammars.is_empty() {
+        paths.push(combined_grammar_wasm_path(extension_dir, options));
+    }
+    paths
+}
+
+/// Writes a gzipped tarball of the contents of `archive_dir`, such as a packaged extension, to
+/// `writer`. This lets an archive be streamed, such as into an upload, without writing it to
+/// disk. The extension CLI writes its `archive.tar.gz` with this as well.
+pub async fn write_extension_archive(
+    archive_dir: &Path,
+    writer: impl io::Write + Unpin + Send + Sync,
+) -> Result<()> {
+    let mut builder = async_tar::Builder::new(GzipEncoder::new(AllowStdIo::new(writer)));
+    builder
+        .append_dir_all(".", archive_dir)
+        .await
+        .with_context(|| format!("failed to archive {}", archive_dir.display()))?;
+    let mut encoder = builder
+        .into_inner()
+        .await
+        .context("failed to finish archive")?;
+    encoder.close().await.context("failed to finish archive")?;
+    Ok(())
+}
+
+/// Returns the path of the wasm that `cargo build` produces for an extension's Rust crate, given
+/// the content of its `Cargo.toml`, before it is encoded as a component and written to the
+/// extension directory.
+pub fn resolved_wasm_artifact_path(
+    extension_dir: &Path,
+    cargo_toml_content: &str,
+    options: &CompileExtensionOptions,
+) -> Result<PathBuf> {
+    let cargo_toml: CargoToml =
+        toml::from_str(cargo_toml_content).context("failed to parse Cargo.toml")?;
+    let mut wasm_path = PathBuf::from(extension_dir);
+    wasm_path.extend([
+        "target",
+        rust_target(options),
+        if options.release { "release" } else { "debug" },
+        &cargo_toml
+            .package
+            .name
+            // The wasm32-wasip2 target normalizes `-` in package names to `_` in the resulting `.wasm` file.
+            .replace('-', "_"),
+    ]);
+    wasm_path.set_extension("wasm");
+    Ok(wasm_path)
+}
+
+fn extension_wasm_path(extension_dir: &Path, options: &CompileExtensionOptions) -> PathBuf {
+    extension_dir.join(
+        options
+            .extension_wasm_file_name
+            .as_deref()
+            .unwrap_or("extension.wasm"),
+    )
+}
+
+fn grammar_wasm_path(
+    extension_dir: &Path,
+    grammar_name: &str,
+    options: &CompileExtensionOptions,
+) -> PathBuf {
+    let mut grammar_wasm_path = match &options.grammar_output_dir {
+        Some(grammar_output_dir) => extension_dir.join(grammar_output_dir),
+        None => extension_dir.join("grammars"),
+    };
+    grammar_wasm_path.push(grammar_name);
+    grammar_wasm_path.set_extension("wasm");
+    grammar_wasm_path
+}
+
+fn combined_grammar_wasm_path(extension_dir: &Path, options: &CompileExtensionOptions) -> PathBuf {
+    match &options.grammar_output_dir {
+        Some(grammar_output_dir) => extension_dir.join(grammar_output_dir),
+        None => extension_dir.to_path_buf(),
+    }
+    .join(COMBINED_GRAMMARS_WASM_FILE_NAME)
+}
+
+/// Reads the grammars of a legacy extension on the v0 schema from the `.toml` files in its
+/// `grammars` directory, keyed by grammar name.
+pub fn read_v0_grammars(extension_dir: &Path) -> Result<HashMap<String, GrammarManifestEntry>> {
+    #[derive(Deserialize)]
+    struct GrammarConfigToml {
+        pub repository: String,
+        pub commit: String,
+        #[serde(default)]
+        pub path: Option<String>,
+    }
+
+    let mut grammars = HashMap::default();
+    let grammars_dir = extension_dir.join("grammars");
+    if !grammars_dir.exists() {
+        return Ok(grammars);
+    }
+
+    for entry in fs::read_dir(&grammars_dir).context("failed to list grammars dir")? {
+        let entry = entry?;
+        let grammar_path = entry.path();
+        if grammar_path.extension() == Some("toml".as_ref()) {
+            let grammar_config = fs::read_to_string(&grammar_path)?;
+            let grammar_config: GrammarConfigToml = toml::from_str(&grammar_config)
+                .with_context(|| format!("invalid grammar config {}", grammar_path.display()))?;
+
+            let grammar_name = grammar_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .context("no grammar name")?;
+            grammars.insert(
+                grammar_name.to_string(),
+                GrammarManifestEntry {
+                    repository: grammar_config.repository,
+                    rev: grammar_config.commit,
+                    path: grammar_config.path,
+                    vendored_path: None,
+                    c_standard: None,
+                    prebuilt_url: None,
+                    extra_exports: Vec::new(),
+                    archive: None,
+                },
+            );
+        }
+    }
+
+    Ok(grammars)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Writes a [`BuildProgressEvent`] to `writer` as a single line of JSON, flushing it so that a
+/// reader sees each event as soon as it happens.
+fn write_progress_json_line(writer: &mut dyn io::Write, event: &BuildProgressEvent) -> Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Adds each directory under `dir`, including `dir` itself, that contains an extension manifest
+/// to `extension_dirs`, in sorted order. Hidden directories and `target` directories are
+/// skipped.
+fn find_extension_dirs(dir: &Path, extension_dirs: &mut Vec<PathBuf>) -> Result<()> {
+    if dir.join("extension.toml").is_file() || dir.join("extension.json").is_file() {
+        extension_dirs.push(dir.to_path_buf());
+        return Ok(());
+    }
+    for path in sorted_dir_entries(dir)? {
+        let Some(file_name) = path.file_name().and_then(OsStr::to_str) else {
+            continue;
+        };
+        if path.is_dir() && !file_name.starts_with('.') && file_name != "target" {
+            find_extension_dirs(&path, extension_dirs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the manifest of the extension in `extension_dir`, from its `extension.toml` or, for
+/// legacy extensions, its `extension.json`.
+fn read_extension_manifest(extension_dir: &Path) -> Result<ExtensionManifest> {
+    let extension_id = extension_dir
+        .file_name()
+        .and_then(OsStr::to_str)
+        .context("invalid extension name")?;
+    let old_manifest_path = extension_dir.join("extension.json");
+    if old_manifest_path.is_file() {
+        let old_manifest_content =
+            fs::read_to_string(&old_manifest_path).context("failed to read extension.json")?;
+        let old_manifest: OldExtensionManifest =
+            serde_json::from_str(&old_manifest_content).context("invalid extension.json")?;
+        return Ok(manifest_from_old_manifest(old_manifest, extension_id));
+    }
+    let manifest_content = fs::read_to_string(extension_dir.join("extension.toml"))
+        .context("failed to read extension.toml")?;
+    toml::from_str(&manifest_content).context("invalid extension.toml")
+}
+
+/// Returns the name of the directory to build an extension in within the cache directory, which
+/// is its ID, after checking that the ID is a single plain path component. The ID comes from the
+/// extension's manifest, so it can't be trusted to stay within the cache directory otherwise.
+fn extension_cache_dir_name(extension_id: &str) -> Result<&str> {
+    let mut components = Path::new(extension_id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None)
+            if name == extension_id && !extension_id.contains(['/', '\\']) =>
+        {
+            Ok(extension_id)
+        }
+        _ => bail!("invalid extension ID {extension_id:?}"),
+    }
+}
+
+/// Returns the names of the host interfaces and functions imported by a compiled extension
+/// component, such as `zed:extension/http-client@0.6.0`.
+///
+/// Only the component's own imports are returned, not those of the modules nested within it,
+/// which are satisfied by the component itself.
+pub fn wasm_extension_imports(wasm_bytes: &[u8]) -> Result<Vec<String>> {
+    use wasmparser::Payload::*;
+
+    let mut imports = Vec::new();
+    let mut depth = 0_usize;
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        match payload.context("error parsing wasm extension")? {
+            ModuleSection { .. } | ComponentSection { .. } => depth += 1,
+            End { .. } => depth = depth.saturating_sub(1),
+            ComponentImportSection(reader) if depth == 0 => {
+                for import in reader {
+                    imports.push(import?.name.0.to_string());
                 }
             }
+            _ => {}
+        }
+    }
+    Ok(imports)
+}
+
+/// Extracts the [`BuildInfo`] embedded into an extension's wasm, if it was stamped with one.
+pub fn read_build_info(wasm_bytes: &[u8]) -> Result<Option<BuildInfo>> {
+    let mut build_info = None;
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == BUILD_INFO_SECTION_NAME {
+                build_info = Some(
+                    serde_json::from_slice(section.data()).context("invalid build info section")?,
+                );
+            }
+        }
+    }
+    Ok(build_info)
+}
+
+/// Appends a [`BuildInfo`] section describing the current build to an extension's wasm. Since
+/// [`read_build_info`] reads the last such section, this supersedes any earlier build info.
+///
+/// The build is timestamped with `source_date_epoch` if it is set, or the current time otherwise.
+fn append_build_info(
+    wasm_bytes: &mut Vec<u8>,
+    source_state: Option<&SourceState>,
+    source_date_epoch: Option<u64>,
+) -> Result<()> {
+    let timestamp = match source_date_epoch {
+        Some(timestamp) => timestamp,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("system time is before the Unix epoch")?
+            .as_secs(),
+    };
+    let build_info = BuildInfo {
+        builder_version: env!("CARGO_PKG_VERSION").to_string(),
+        timestamp,
+        source_commit: source_state.map(|state| state.commit.clone()),
+        source_dirty: source_state.is_some_and(|state| state.dirty),
+    };
+    let build_info_section = wasm_encoder::CustomSection {
+        name: BUILD_INFO_SECTION_NAME.into(),
+        data: serde_json::to_vec(&build_info)?.into(),
+    };
+    wasm_bytes.push(build_info_section.id());
+    build_info_section.encode(wasm_bytes);
+    Ok(())
+}
+
+/// Extracts the manifest embedded into an extension's wasm, if it has one.
+pub fn read_embedded_manifest(wasm_bytes: &[u8]) -> Result<Option<ExtensionManifest>> {
+    let mut manifest = None;
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        if let wasmparser::Payload::CustomSection(section) = payload? {
+            if section.name() == MANIFEST_SECTION_NAME {
+                let manifest_content = std::str::from_utf8(section.data())
+                    .context("invalid embedded manifest section")?;
+                manifest =
+                    Some(toml::from_str(manifest_content).context("invalid embedded manifest")?);
+            }
         }
     }
+    Ok(manifest)
+}
+
+/// Checks that the manifest embedded into an extension's wasm, if it has one, agrees with the
+/// extension's manifest on the extension's identity and on the features that its wasm implements.
+pub fn verify_embedded_manifest(wasm_bytes: &[u8], manifest: &ExtensionManifest) -> Result<()> {
+    let Some(embedded_manifest) = read_embedded_manifest(wasm_bytes)? else {
+        return Ok(());
+    };
+
+    let mut mismatches = Vec::new();
+    if embedded_manifest.id != manifest.id {
+        mismatches.push(format!(
+            "id is {:?}, but the manifest has {:?}",
+            embedded_manifest.id, manifest.id
+        ));
+    }
+    if embedded_manifest.name != manifest.name {
+        mismatches.push(format!(
+            "name is {:?}, but the manifest has {:?}",
+            embedded_manifest.name, manifest.name
+        ));
+    }
+    if embedded_manifest.version != manifest.version {
+        mismatches.push(format!(
+            "version is {:?}, but the manifest has {:?}",
+            embedded_manifest.version, manifest.version
+        ));
+    }
+    if !embedded_manifest
+        .language_servers
+        .keys()
+        .eq(manifest.language_servers.keys())
+    {
+        mismatches.push("language servers differ from the manifest's".to_string());
+    }
+    if !embedded_manifest
+        .context_servers
+        .keys()
+        .eq(manifest.context_servers.keys())
+    {
+        mismatches.push("context servers differ from the manifest's".to_string());
+    }
+    if !embedded_manifest
+        .slash_commands
+        .keys()
+        .eq(manifest.slash_commands.keys())
+    {
+        mismatches.push("slash commands differ from the manifest's".to_string());
+    }
 
+    if !mismatches.is_empty() {
+        bail!(
+            "the manifest embedded in extension {}'s wasm doesn't match its manifest:\n{}",
+            manifest.id,
+            mismatches.join("\n")
+        );
+    }
     Ok(())
 }
+
+fn wasm_exports(wasm_bytes: &[u8]) -> Result<Vec<String>> {
+    let mut exports = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        if let wasmparser::Payload::ExportSection(reader) = payload? {
+            for export in reader {
+                exports.push(export?.name.to_string());
+            }
+        }
+    }
+    Ok(exports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{
+        download::verify_integrity,
+        git::{add_git_config_env, grammar_rev, local_grammar_repository_dir},
+        grammar::{
+            copy_grammar_sources, duplicate_grammar_symbols, insert_grammar_vendored_path,
+            is_clang_cl, parse_clang_diagnostics, record_grammar_clang_output,
+            unsupported_grammar_imports,
+        },
+        rust::{
+            build_plan_from_metadata, cargo_lock_package_count, extension_api_dependency_version,
+            rustflags, verify_cdylib_crate_type,
+        },
+        validate::{icon_theme_icon_paths, missing_theme_fields, normalize_theme_colors},
+    };
+    use wasm_encoder::{
+        CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, Module,
+        TypeSection,
+    };
+
+    fn module_exporting(export_name: &str) -> Vec<u8> {
+        module_exporting_all(&[export_name])
+    }
+
+    fn module_exporting_all(export_names: &[&str]) -> Vec<u8> {
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        let mut exports = ExportSection::new();
+        for export_name in export_names {
+            exports.export(export_name, ExportKind::Func, 0);
+        }
+        let mut code = CodeSection::new();
+        let mut function = Function::new([]);
+        function.instruction(&Instruction::End);
+        code.function(&function);
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&functions)
+            .section(&exports)
+            .section(&code);
+        module.finish()
+    }
+
+    #[test]
+    fn test_compile_output_diff() {
+        let old = CompileOutput {
+            extension_wasm_size: Some(1000),
+            grammar_wasm_sizes: BTreeMap::from_iter([("json".into(), 500), ("toml".into(), 300)]),
+            ..Default::default()
+        };
+        let new = CompileOutput {
+            extension_wasm_size: Some(800),
+            grammar_wasm_sizes: BTreeMap::from_iter([("json".into(), 600), ("yaml".into(), 200)]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            old.diff(&new),
+            BuildDiff {
+                extension_wasm_size_delta: -200,
+                grammar_size_deltas: BTreeMap::from_iter([("json".into(), 100)]),
+                added_grammars: vec!["yaml".into()],
+                removed_grammars: vec!["toml".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_icon_theme_icon_paths() {
+        let icon_theme_family: IconThemeFamilyContent = serde_json_lenient::from_str(
+            r#"{
+                "name": "Icons",
+                "author": "Zed",
+                "themes": [{
+                    "name": "Icons Dark",
+                    "appearance": "dark",
+                    "directory_icons": { "collapsed": "icons/folder.svg" },
+                    "chevron_icons": { "expanded": "icons/chevron.svg" },
+                    "file_icons": {
+                        "rust": { "path": "icons/rust.svg" },
+                        "toml": { "path": "icons/folder.svg" }
+                    }
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            icon_theme_icon_paths(&icon_theme_family.themes[0]),
+            ["icons/chevron.svg", "icons/folder.svg", "icons/rust.svg"]
+        );
+    }
+
+    #[test]
+    fn test_validate_against_host() {
+        let output = CompileOutput {
+            extension_api_version: Some(SemanticVersion::new(0, 6, 0)),
+            grammar_abi_versions: BTreeMap::from_iter([("json".into(), 14), ("toml".into(), 12)]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            output.validate_against_host(
+                &wasm_api_version_range(),
+                &SUPPORTED_GRAMMAR_ABI_VERSIONS
+            ),
+            vec![
+                "grammar 'toml' targets Tree-sitter ABI version 12, but the host supports versions 13 through 15"
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            output.validate_against_host(
+                &(SemanticVersion::new(0, 1, 0)..=SemanticVersion::new(0, 5, 0)),
+                &(11..=14)
+            ),
+            vec![
+                "extension targets extension API version 0.6.0, but the host supports versions 0.1.0 through 0.5.0"
+                    .to_string()
+            ]
+        );
+        assert_eq!(
+            output
+                .validate_against_host(
+                    &(SemanticVersion::new(0, 7, 0)..=SemanticVersion::new(0, 8, 0)),
+                    &(13..=13)
+                )
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_local_grammar_repository_dir() {
+        let extension_dir = Path::new("/extensions/foo");
+        assert_eq!(
+            local_grammar_repository_dir(extension_dir, "../tree-sitter-foo"),
+            Some(extension_dir.join("../tree-sitter-foo"))
+        );
+        assert_eq!(
+            local_grammar_repository_dir(extension_dir, "https://github.com/foo/bar"),
+            None
+        );
+        assert_eq!(
+            local_grammar_repository_dir(extension_dir, "git@github.com:foo/bar.git"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extension_api_dependency_version() {
+        let version = |cargo_toml| extension_api_dependency_version(cargo_toml).unwrap();
+
+        assert_eq!(
+            version("[dependencies]\nzed_extension_api = \"0.5.0\""),
+            Some(SemanticVersion::new(0, 5, 0))
+        );
+        assert_eq!(
+            version("[dependencies]\nzed_extension_api = { version = \"=0.6\" }"),
+            Some(SemanticVersion::new(0, 6, 0))
+        );
+        assert_eq!(
+            version("[dependencies]\nzed_extension_api = { path = \"../api\" }"),
+            None
+        );
+        assert_eq!(
+            version("[dependencies]\nzed_extension_api = \">=0.1, <0.7\""),
+            None
+        );
+        assert_eq!(version("[dependencies]\nserde = \"1.0\""), None);
+    }
+
+    #[test]
+    fn test_verify_cdylib_crate_type() {
+        assert!(verify_cdylib_crate_type("[lib]\ncrate-type = [\"cdylib\"]").is_ok());
+        assert!(verify_cdylib_crate_type("[lib]\ncrate-type = [\"rlib\", \"cdylib\"]").is_ok());
+        assert!(verify_cdylib_crate_type("[lib]\npath = \"src/lib.rs\"").is_err());
+        assert!(verify_cdylib_crate_type("[package]\nname = \"my-extension\"").is_err());
+    }
+
+    #[test]
+    fn test_duplicate_grammar_symbols() {
+        let object_grammars = [
+            (PathBuf::from("/cache/a.o"), Arc::from("foo")),
+            (PathBuf::from("/cache/b.o"), Arc::from("foo")),
+            (PathBuf::from("/cache/c.o"), Arc::from("bar")),
+        ];
+        let stderr = "wasm-ld: error: duplicate symbol: serialize\n\
+            >>> defined in /cache/b.o\n\
+            >>> defined in /cache/c.o\n\
+            clang: error: linker command failed with exit code 1\n";
+        assert_eq!(
+            duplicate_grammar_symbols(stderr, &object_grammars),
+            ["`serialize` is defined by 'foo' and 'bar'"]
+        );
+        assert!(
+            duplicate_grammar_symbols("wasm-ld: error: undefined symbol: x", &object_grammars)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_parse_clang_diagnostics() {
+        let stderr = "src/scanner.c:12:5: warning: unused variable 'x' [-Wunused-variable]\n   \
+            12 |     int x;\n\
+            src/parser.c:3:10: fatal error: 'tree_sitter/parser.h' file not found\n\
+            clang: error: linker command failed with exit code 1\n";
+
+        assert_eq!(
+            parse_clang_diagnostics(stderr),
+            [
+                CompileDiagnostic {
+                    level: "warning".into(),
+                    message: "unused variable 'x' [-Wunused-variable]".into(),
+                    file: Some("src/scanner.c".into()),
+                    line: Some(12),
+                    column: Some(5),
+                },
+                CompileDiagnostic {
+                    level: "fatal error".into(),
+                    message: "'tree_sitter/parser.h' file not found".into(),
+                    file: Some("src/parser.c".into()),
+                    line: Some(3),
+                    column: Some(10),
+                },
+                CompileDiagnostic {
+                    level: "error".into(),
+                    message: "linker command failed with exit code 1".into(),
+                    file: None,
+                    line: None,
+                    column: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_wasm_features() {
+        let mut validator = Validator::new_with_features(HOST_WASM_FEATURES);
+        assert!(
+            validator
+                .validate_all(&module_exporting("tree_sitter_rust"))
+                .is_ok()
+        );
+
+        let mut memories = wasm_encoder::MemorySection::new();
+        memories.memory(wasm_encoder::MemoryType {
+            minimum: 1,
+            maximum: Some(1),
+            memory64: false,
+            shared: true,
+            page_size_log2: None,
+        });
+        let mut module = Module::new();
+        module.section(&memories);
+        let Err(error) =
+            Validator::new_with_features(HOST_WASM_FEATURES).validate_all(&module.finish())
+        else {
+            panic!("expected a shared memory to be rejected");
+        };
+        assert!(error.to_string().contains("threads"), "{error}");
+    }
+
+    #[test]
+    fn test_build_plan_from_metadata() {
+        let metadata = serde_json::json!({
+            "packages": [
+                {
+                    "id": "ext",
+                    "name": "my-extension",
+                    "version": "0.1.0",
+                    "source": null,
+                    "targets": [{ "kind": ["cdylib"] }],
+                },
+                {
+                    "id": "api",
+                    "name": "zed_extension_api",
+                    "version": "0.6.0",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "targets": [{ "kind": ["lib"] }, { "kind": ["custom-build"] }],
+                },
+                {
+                    "id": "test-util",
+                    "name": "test-util",
+                    "version": "1.0.0",
+                    "source": "registry+https://github.com/rust-lang/crates.io-index",
+                    "targets": [{ "kind": ["lib"] }],
+                },
+            ],
+            "resolve": {
+                "root": "ext",
+                "nodes": [
+                    {
+                        "id": "ext",
+                        "deps": [
+                            { "pkg": "api", "dep_kinds": [{ "kind": null }] },
+                            { "pkg": "test-util", "dep_kinds": [{ "kind": "dev" }] },
+                        ],
+                    },
+                    { "id": "api", "deps": [] },
+                    { "id": "test-util", "deps": [] },
+                ],
+            },
+        });
+
+        let plan = build_plan_from_metadata(metadata.to_string().as_bytes()).unwrap();
+        assert_eq!(
+            plan,
+            [
+                PlannedCrate {
+                    name: "my-extension".into(),
+                    version: "0.1.0".into(),
+                    source: None,
+                    has_build_script: false,
+                },
+                PlannedCrate {
+                    name: "zed_extension_api".into(),
+                    version: "0.6.0".into(),
+                    source: Some("registry+https://github.com/rust-lang/crates.io-index".into()),
+                    has_build_script: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolved_wasm_artifact_path() {
+        let cargo_toml = "[package]\nname = \"my-extension\"\nversion = \"0.1.0\"";
+        assert_eq!(
+            resolved_wasm_artifact_path(
+                Path::new("/extension"),
+                cargo_toml,
+                &CompileExtensionOptions::default()
+            )
+            .unwrap(),
+            Path::new("/extension/target/wasm32-wasip2/debug/my_extension.wasm")
+        );
+        assert_eq!(
+            resolved_wasm_artifact_path(
+                Path::new("/extension"),
+                cargo_toml,
+                &CompileExtensionOptions {
+                    release: true,
+                    wasip1: true,
+                    ..Default::default()
+                }
+            )
+            .unwrap(),
+            Path::new("/extension/target/wasm32-wasip1/release/my_extension.wasm")
+        );
+    }
+
+    #[test]
+    fn test_cargo_lock_package_count() {
+        let cargo_lock = r#"
+            version = 4
+
+            [[package]]
+            name = "my-extension"
+            version = "0.1.0"
+
+            [[package]]
+            name = "zed_extension_api"
+            version = "0.6.0"
+        "#;
+        assert_eq!(cargo_lock_package_count(cargo_lock), Some(2));
+        assert_eq!(cargo_lock_package_count("version = 4"), None);
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let integrity = "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+        assert!(verify_integrity(b"", integrity).is_ok());
+        assert_eq!(
+            verify_integrity(b"grammar", integrity)
+                .unwrap_err()
+                .to_string(),
+            "expected sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=, \
+            but the content has hash sha256-4F64pKJVdD4FHXfV5Dt15eOAc7J1ObAYT7zjUkBaPuE="
+        );
+        assert!(verify_integrity(b"", "md5-1B2M2Y8AsgTpgAmY7PhCfg==").is_err());
+    }
+
+    #[test]
+    fn test_check_grammar_repository_allowed() {
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://example.com/tree-sitter-foo")
+                .is_ok()
+        );
+
+        builder.allowed_grammar_repositories = vec!["https://github.com/".into()];
+        builder.denied_grammar_repositories = vec!["https://github.com/evil/".into()];
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://github.com/tree-sitter/tree-sitter-rust")
+                .is_ok()
+        );
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://github.com.example.com/tree-sitter-foo")
+                .is_err()
+        );
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://github.com/evil/tree-sitter-foo")
+                .is_err()
+        );
+        for url in [
+            "https://github.com.evil.com/tree-sitter-foo",
+            "HTTPS://evil.com/tree-sitter-foo",
+            "https://user@evil.com/",
+            "https://github.com@evil.com/tree-sitter-foo",
+            "git@evil.com:x",
+            "git@github.com:tree-sitter/tree-sitter-rust",
+            "http://github.com/tree-sitter/tree-sitter-rust",
+            "https://github.com:8443/tree-sitter/tree-sitter-rust",
+        ] {
+            assert!(
+                builder.check_grammar_repository_allowed(url).is_err(),
+                "{url} should not be allowed"
+            );
+        }
+        for url in [
+            "HTTPS://GitHub.com/tree-sitter/tree-sitter-rust",
+            "https://github.com:443/tree-sitter/tree-sitter-rust",
+        ] {
+            assert!(
+                builder.check_grammar_repository_allowed(url).is_ok(),
+                "{url} should be allowed"
+            );
+        }
+
+        // Rules without a trailing `/` only match at a path boundary.
+        builder.allowed_grammar_repositories = vec!["https://github.com/tree-sitter".into()];
+        builder.denied_grammar_repositories = vec!["https://EVIL.com".into()];
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://github.com/tree-sitter/tree-sitter-rust")
+                .is_ok()
+        );
+        assert!(
+            builder
+                .check_grammar_repository_allowed("https://github.com/tree-sitter-evil/foo")
+                .is_err()
+        );
+        assert!(
+            builder
+                .check_grammar_repository_allowed("HTTPS://evil.com:443/tree-sitter/foo")
+                .unwrap_err()
+                .to_string()
+                .contains("is denied by the rule")
+        );
+
+        // Local repositories can't be checked against the allowed URLs.
+        let local_grammar = GrammarManifestEntry {
+            repository: "../tree-sitter-foo".into(),
+            rev: "main".into(),
+            ..Default::default()
+        };
+        let extension_dir = Path::new("/extension");
+        assert!(
+            builder
+                .grammar_source_dir(extension_dir, extension_dir, "foo", &local_grammar)
+                .unwrap_err()
+                .to_string()
+                .contains("only repositories at allowed URLs may be used")
+        );
+    }
+
+    #[test]
+    fn test_cached_wasi_sysroot_dir() {
+        let builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        assert_eq!(
+            builder.cached_wasi_sysroot_dir("ABC123"),
+            Path::new("/cache/wasi-sysroot/wasi-sysroot-25.0-abc123")
+        );
+        assert_ne!(
+            builder.cached_wasi_sysroot_dir("abc123"),
+            builder.cached_wasi_sysroot_dir("def456")
+        );
+    }
+
+    #[test]
+    fn test_grammar_rev() {
+        let grammar = |rev: &str| GrammarManifestEntry {
+            repository: "https://github.com/tree-sitter/tree-sitter-rust".into(),
+            rev: rev.into(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            grammar_rev("rust", &grammar(" v0.21.0\n")).unwrap(),
+            "v0.21.0"
+        );
+        assert_eq!(
+            grammar_rev("rust", &grammar("  ")).unwrap_err().to_string(),
+            "grammar rust has an empty revision"
+        );
+    }
+
+    #[test]
+    fn test_is_clang_cl() {
+        assert!(is_clang_cl(Path::new("C:/LLVM/bin/clang-cl.exe")));
+        assert!(is_clang_cl(Path::new("/usr/bin/clang-cl")));
+        assert!(!is_clang_cl(Path::new("/opt/wasi-sdk/bin/clang")));
+    }
+
+    #[test]
+    fn test_wasm_exports() {
+        let exports = wasm_exports(&module_exporting("tree_sitter_rust")).unwrap();
+        assert_eq!(exports, ["tree_sitter_rust"]);
+        assert!(wasm_exports(b"not wasm").is_err());
+    }
+
+    #[test]
+    fn test_unsupported_grammar_imports() {
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        let mut imports = wasm_encoder::ImportSection::new();
+        imports.import("env", "malloc", wasm_encoder::EntityType::Function(0));
+        imports.import("env", "fopen", wasm_encoder::EntityType::Function(0));
+        imports.import(
+            "env",
+            "__memory_base",
+            wasm_encoder::EntityType::Global(wasm_encoder::GlobalType {
+                val_type: wasm_encoder::ValType::I32,
+                mutable: false,
+                shared: false,
+            }),
+        );
+        let mut module = Module::new();
+        module.section(&types).section(&imports);
+
+        let allowed_imports = DEFAULT_ALLOWED_GRAMMAR_IMPORTS
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unsupported_grammar_imports(&module.finish(), &allowed_imports).unwrap(),
+            ["fopen"]
+        );
+    }
+
+    #[test]
+    fn test_copy_grammar_sources() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repository_dir = temp_dir.path().join("repository");
+        for (path, content) in [
+            ("grammar.js", "module.exports = grammar({});"),
+            ("bindings/c/binding.c", ""),
+            ("common/scanner.h", ""),
+            ("other/src/parser.c", ""),
+            ("typescript/src/parser.c", ""),
+            ("typescript/src/scanner.c", ""),
+            ("typescript/src/tree_sitter/parser.h", ""),
+            ("typescript/src/grammar.json", "{}"),
+            ("typescript/src/node-types.json", "[]"),
+        ] {
+            let path = repository_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let vendored_dir = temp_dir.path().join("vendored");
+        copy_grammar_sources(
+            &repository_dir,
+            &repository_dir.join("typescript"),
+            &vendored_dir,
+        )
+        .unwrap();
+
+        let mut vendored_files = Vec::new();
+        let mut dirs = vec![vendored_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    vendored_files.push(path.strip_prefix(&vendored_dir).unwrap().to_path_buf());
+                }
+            }
+        }
+        vendored_files.sort();
+        assert_eq!(
+            vendored_files,
+            [
+                "typescript/src/parser.c",
+                "typescript/src/scanner.c",
+                "typescript/src/tree_sitter/parser.h",
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_insert_grammar_vendored_path() {
+        let manifest = r#"
+            id = "test"
+
+            # Our grammar.
+            [grammars.test] # pinned
+            repository = "https://github.com/example/tree-sitter-test"
+            rev = "abc"
+        "#;
+        assert_eq!(
+            insert_grammar_vendored_path(manifest, "test", "vendor/grammars/test").unwrap(),
+            r#"
+            id = "test"
+
+            # Our grammar.
+            [grammars.test] # pinned
+            vendored_path = "vendor/grammars/test"
+            repository = "https://github.com/example/tree-sitter-test"
+            rev = "abc"
+        "#
+        );
+
+        let inline_manifest = r#"
+            [grammars]
+            test = { repository = "https://github.com/example/tree-sitter-test", rev = "abc" }
+        "#;
+        assert!(
+            insert_grammar_vendored_path(inline_manifest, "test", "vendor/grammars/test").is_err()
+        );
+    }
+
+    #[test]
+    fn test_wasm_extension_imports() {
+        let mut imports = wasm_encoder::ComponentImportSection::new();
+        imports.import(
+            "zed:extension/http-client@0.6.0",
+            wasm_encoder::ComponentTypeRef::Instance(0),
+        );
+        let mut nested_component = wasm_encoder::Component::new();
+        nested_component.section(&{
+            let mut imports = wasm_encoder::ComponentImportSection::new();
+            imports.import("nested", wasm_encoder::ComponentTypeRef::Instance(0));
+            imports
+        });
+        let mut component = wasm_encoder::Component::new();
+        component
+            .section(&imports)
+            .section(&wasm_encoder::NestedComponentSection(&nested_component));
+
+        assert_eq!(
+            wasm_extension_imports(&component.finish()).unwrap(),
+            ["zed:extension/http-client@0.6.0"]
+        );
+    }
+
+    #[test]
+    fn test_verify_embedded_manifest() {
+        let manifest_content =
+            "id = \"foo\"\nname = \"Foo\"\nversion = \"1.0.0\"\nschema_version = 1\n";
+        let manifest: ExtensionManifest = toml::from_str(manifest_content).unwrap();
+        let mut module = wasm_encoder::Module::new();
+        module.section(&wasm_encoder::CustomSection {
+            name: MANIFEST_SECTION_NAME.into(),
+            data: manifest_content.as_bytes().into(),
+        });
+        let wasm_bytes = module.finish();
+
+        assert_eq!(
+            read_embedded_manifest(&wasm_bytes).unwrap().as_ref(),
+            Some(&manifest)
+        );
+        assert!(verify_embedded_manifest(&wasm_bytes, &manifest).is_ok());
+        assert!(verify_embedded_manifest(&module_exporting("tree_sitter_rust"), &manifest).is_ok());
+
+        let newer_manifest = ExtensionManifest {
+            version: "1.1.0".into(),
+            ..manifest
+        };
+        let error = verify_embedded_manifest(&wasm_bytes, &newer_manifest).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("version is \"1.0.0\", but the manifest has \"1.1.0\""),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_write_progress_json_line() {
+        let mut output = Vec::new();
+        write_progress_json_line(
+            &mut output,
+            &BuildProgressEvent {
+                phase: BuildPhase::GrammarCompiled {
+                    grammar_name: "rust".into(),
+                },
+                fraction_complete: 0.5,
+            },
+        )
+        .unwrap();
+        write_progress_json_line(
+            &mut output,
+            &BuildProgressEvent {
+                phase: BuildPhase::RustExtensionCompiled,
+                fraction_complete: 1.0,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"phase\":{\"kind\":\"grammar_compiled\",\"grammar_name\":\"rust\"},\"fraction_complete\":0.5}\n\
+            {\"phase\":{\"kind\":\"rust_extension_compiled\"},\"fraction_complete\":1.0}\n"
+        );
+    }
+
+    #[test]
+    fn test_extension_cache_dir_name() {
+        assert_eq!(extension_cache_dir_name("rust").unwrap(), "rust");
+        assert_eq!(extension_cache_dir_name("my-ext.v2").unwrap(), "my-ext.v2");
+        for extension_id in ["", ".", "..", "../../home/x", "a/b", "a\\b", "/abs"] {
+            assert!(
+                extension_cache_dir_name(extension_id).is_err(),
+                "{extension_id:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rustflags() {
+        assert!(
+            rustflags(&CompileExtensionOptions::default())
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            rustflags(&CompileExtensionOptions {
+                rust_target_features: vec!["+simd128".into(), "-bulk-memory".into()],
+                pgo: Some(PgoPhase::Instrument {
+                    profile_dir: PathBuf::from("/profiles"),
+                }),
+                ..Default::default()
+            })
+            .unwrap(),
+            [
+                "-C",
+                "target-feature=+simd128,-bulk-memory",
+                "-C",
+                "profile-generate=/profiles",
+            ]
+        );
+        assert!(
+            rustflags(&CompileExtensionOptions {
+                pgo: Some(PgoPhase::Optimize {
+                    profile_path: PathBuf::from("/nonexistent/extension.profdata"),
+                }),
+                ..Default::default()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_append_build_info() {
+        let mut wasm = module_exporting("tree_sitter_rust");
+        append_build_info(&mut wasm, None, None).unwrap();
+        append_build_info(
+            &mut wasm,
+            Some(&SourceState {
+                commit: "abc123".into(),
+                dirty: false,
+            }),
+            Some(1_700_000_000),
+        )
+        .unwrap();
+        let build_info = read_build_info(&wasm).unwrap().unwrap();
+        assert_eq!(build_info.source_commit, Some("abc123".into()));
+        assert_eq!(build_info.timestamp, 1_700_000_000);
+    }
+
+    #[test]
+    fn test_read_build_info() {
+        let build_info = BuildInfo {
+            builder_version: "0.1.0".into(),
+            timestamp: 1_700_000_000,
+            source_commit: Some("abc123".into()),
+            source_dirty: true,
+        };
+        let mut module = wasm_encoder::Module::new();
+        module.section(&wasm_encoder::CustomSection {
+            name: BUILD_INFO_SECTION_NAME.into(),
+            data: serde_json::to_vec(&build_info).unwrap().into(),
+        });
+
+        assert_eq!(read_build_info(&module.finish()).unwrap(), Some(build_info));
+        assert_eq!(
+            read_build_info(&module_exporting("tree_sitter_rust")).unwrap(),
+            None
+        );
+    }
+
+    /// A [`CommandRunner`] that returns canned outputs, in order, and records the arguments of
+    /// each command it runs. Running a command once the outputs have run out is an error that
+    /// names the command.
+    #[derive(Default)]
+    struct FakeCommandRunner {
+        stdouts: std::sync::Mutex<Vec<&'static str>>,
+        commands: std::sync::Mutex<Vec<Vec<String>>>,
+        /// Commands whose arguments this returns `true` for exit unsuccessfully.
+        fails: Option<fn(&[String]) -> bool>,
+        /// Whether successful commands write a wasm to the path after their `-o` argument, as
+        /// Clang would, exporting the symbols passed to them with `-Wl,--export=`.
+        writes_clang_outputs: bool,
+    }
+
+    impl CommandRunner for FakeCommandRunner {
+        fn output(&self, command: &mut Command) -> io::Result<Output> {
+            let args = command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            let status = if self.fails.is_some_and(|fails| fails(&args)) {
+                failed_exit_status()
+            } else {
+                Default::default()
+            };
+            let mut stdouts = self.stdouts.lock().unwrap();
+            if stdouts.is_empty() {
+                return Err(io::Error::other(format!(
+                    "no output queued for `{} {}`",
+                    command.get_program().to_string_lossy(),
+                    args.join(" ")
+                )));
+            }
+            let stdout = stdouts.remove(0);
+            if self.writes_clang_outputs && status.success() {
+                if let Some(output_path) = args
+                    .iter()
+                    .position(|arg| arg == "-o")
+                    .and_then(|ix| args.get(ix + 1))
+                {
+                    let export_names = args
+                        .iter()
+                        .filter_map(|arg| arg.strip_prefix("-Wl,--export="))
+                        .collect::<Vec<_>>();
+                    fs::write(output_path, module_exporting_all(&export_names))?;
+                }
+            }
+            self.commands.lock().unwrap().push(args);
+            Ok(Output {
+                status,
+                stdout: stdout.into(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    fn failed_exit_status() -> std::process::ExitStatus {
+        #[cfg(unix)]
+        {
+            std::os::unix::process::ExitStatusExt::from_raw(1 << 8)
+        }
+        #[cfg(windows)]
+        {
+            std::os::windows::process::ExitStatusExt::from_raw(1)
+        }
+    }
+
+    #[test]
+    fn test_checkout_repo_falls_back_to_full_fetch() {
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![
+                "origin\thttps://github.com/tree-sitter/tree-sitter-rust (fetch)\n",
+                "",
+                "",
+                "",
+            ]),
+            fails: Some(|args| args.iter().any(|arg| arg == "--depth")),
+            ..Default::default()
+        });
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        builder.command_runner = runner.clone();
+        builder.git_url_rewrites = vec![GitUrlRewrite {
+            from_prefix: "https://github.com/".into(),
+            to_prefix: "https://mirror.example.com/github/".into(),
+        }];
+
+        // An existing directory, so that the fake remote is checked rather than a clone created.
+        let temp_dir = tempfile::tempdir().unwrap();
+        let directory = temp_dir.path();
+        builder
+            .checkout_repo(
+                directory,
+                "https://github.com/tree-sitter/tree-sitter-rust",
+                "v0.21.0",
+            )
+            .unwrap();
+
+        let git_dir = directory.join(".git").to_string_lossy().into_owned();
+        let mirror_config = "url.https://mirror.example.com/github/.insteadOf=https://github.com/";
+        assert_eq!(
+            runner.commands.lock().unwrap()[1..],
+            [
+                vec![
+                    "-c",
+                    mirror_config,
+                    "--git-dir",
+                    &git_dir,
+                    "fetch",
+                    "--depth",
+                    "1",
+                    "origin",
+                    "v0.21.0",
+                ],
+                vec![
+                    "-c",
+                    mirror_config,
+                    "--git-dir",
+                    &git_dir,
+                    "fetch",
+                    "--tags",
+                    "origin",
+                    "+refs/heads/*:refs/remotes/origin/*",
+                ],
+                vec!["--git-dir", &git_dir, "checkout", "v0.21.0"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_grammar_revs() {
+        let tag_sha = "1".repeat(40);
+        let commit_sha = "2".repeat(40);
+        let branch_sha = "3".repeat(40);
+        let ls_remote_output = format!(
+            "{branch_sha}\trefs/heads/feature/v1.0\n\
+            {tag_sha}\trefs/tags/v1.0\n\
+            {commit_sha}\trefs/tags/v1.0^{{}}\n\
+            {branch_sha}\trefs/heads/main\n\
+            {tag_sha}\trefs/tags/main\n"
+        );
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![
+                ls_remote_output.clone().leak(),
+                ls_remote_output.leak(),
+            ]),
+            ..Default::default()
+        });
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        builder.command_runner = runner.clone();
+
+        let manifest = |rev: &str| -> ExtensionManifest {
+            toml::from_str(&format!(
+                "id = \"foo\"\nname = \"Foo\"\nversion = \"1.0.0\"\nschema_version = 1\n\
+                [grammars.foo]\n\
+                repository = \"https://github.com/tree-sitter/tree-sitter-foo\"\n\
+                rev = \"{rev}\"\n"
+            ))
+            .unwrap()
+        };
+
+        // A tag resolves to the commit it points to, ignoring branches that merely end with it.
+        assert_eq!(
+            builder.resolve_grammar_revs(&manifest("v1.0")).unwrap()["foo"],
+            commit_sha
+        );
+        assert_eq!(
+            runner.commands.lock().unwrap()[0][1..],
+            [
+                "https://github.com/tree-sitter/tree-sitter-foo",
+                "v1.0",
+                "v1.0^{}"
+            ]
+        );
+
+        // A tag and a branch with the same name are ambiguous.
+        assert!(
+            builder
+                .resolve_grammar_revs(&manifest("main"))
+                .unwrap_err()
+                .chain()
+                .any(|error| error.to_string().contains("is ambiguous"))
+        );
+
+        // Commit SHAs are used as-is, without querying the remote.
+        let sha = "ABCDEF".repeat(6) + "0123";
+        assert_eq!(
+            builder.resolve_grammar_revs(&manifest(&sha)).unwrap()["foo"],
+            sha.to_lowercase()
+        );
+        assert_eq!(runner.commands.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_git_command_for_url() {
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        builder.sandbox_builds = true;
+        builder.git_credentials = vec![
+            GitCredential {
+                url_prefix: "https://github.com/my-org/".into(),
+                username: None,
+                token: "github-token".into(),
+            },
+            GitCredential {
+                url_prefix: "https://gitlab.example.com/".into(),
+                username: Some("oauth2".into()),
+                token: "gitlab-token".into(),
+            },
+            GitCredential {
+                url_prefix: "https://git.example.com/".into(),
+                username: None,
+                token: "other-token".into(),
+            },
+        ];
+        let config_env = |command: &Command| {
+            command
+                .get_envs()
+                .filter(|(name, _)| name.to_string_lossy().starts_with("GIT_CONFIG_"))
+                .map(|(name, value)| {
+                    (
+                        name.to_string_lossy().into_owned(),
+                        value.map(|value| value.to_string_lossy().into_owned()),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let authorization = |credentials: &str| {
+            Some(format!(
+                "Authorization: Basic {}",
+                BASE64_STANDARD.encode(credentials)
+            ))
+        };
+
+        let url = "https://github.com/my-org/tree-sitter-foo";
+        let command = builder.git_command_for_url(url).unwrap();
+        assert_eq!(
+            config_env(&command),
+            BTreeMap::from_iter([
+                ("GIT_CONFIG_COUNT".to_string(), Some("1".to_string())),
+                (
+                    "GIT_CONFIG_KEY_0".to_string(),
+                    Some(format!("http.{url}.extraHeader"))
+                ),
+                (
+                    "GIT_CONFIG_VALUE_0".to_string(),
+                    authorization("x-access-token:github-token")
+                ),
+            ])
+        );
+
+        let url = "https://gitlab.example.com/my-org/tree-sitter-foo";
+        let command = builder.git_command_for_url(url).unwrap();
+        assert_eq!(
+            config_env(&command)["GIT_CONFIG_VALUE_0"],
+            authorization("oauth2:gitlab-token")
+        );
+
+        // Only GitHub has a default username.
+        assert!(
+            builder
+                .git_command_for_url("https://git.example.com/my-org/tree-sitter-foo")
+                .is_err()
+        );
+
+        // Existing config entries are kept.
+        let mut command = Command::new("git");
+        add_git_config_env(&mut command, Some("2".into()), "http.extraHeader", "foo").unwrap();
+        add_git_config_env(&mut command, Some("2".into()), "http.sslVerify", "true").unwrap();
+        assert_eq!(
+            config_env(&command),
+            BTreeMap::from_iter([
+                ("GIT_CONFIG_COUNT".to_string(), Some("4".to_string())),
+                (
+                    "GIT_CONFIG_KEY_2".to_string(),
+                    Some("http.extraHeader".to_string())
+                ),
+                ("GIT_CONFIG_VALUE_2".to_string(), Some("foo".to_string())),
+                (
+                    "GIT_CONFIG_KEY_3".to_string(),
+                    Some("http.sslVerify".to_string())
+                ),
+                ("GIT_CONFIG_VALUE_3".to_string(), Some("true".to_string())),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_record_grammar_clang_output() {
+        let clang_stderr = "src/scanner.c:3:5: warning: unused variable 'x' [-Wunused-variable]\n\
+            1 warning generated.\n";
+        let mut compile_output = CompileOutput::default();
+        record_grammar_clang_output(
+            "foo",
+            clang_stderr,
+            &CompileExtensionOptions::default(),
+            &mut compile_output,
+        )
+        .unwrap();
+        assert_eq!(
+            compile_output.grammar_warnings["foo"],
+            ["src/scanner.c:3:5: warning: unused variable 'x' [-Wunused-variable]"]
+        );
+
+        let error = record_grammar_clang_output(
+            "foo",
+            clang_stderr,
+            &CompileExtensionOptions {
+                deny_grammar_warnings: true,
+                ..Default::default()
+            },
+            &mut CompileOutput::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("warnings are denied"), "{error}");
+    }
+
+    #[test]
+    fn test_write_extension_archive() {
+        let archive_dir = tempfile::tempdir().unwrap();
+        fs::write(archive_dir.path().join("extension.toml"), "id = \"foo\"\n").unwrap();
+        fs::create_dir_all(archive_dir.path().join("grammars")).unwrap();
+        fs::write(archive_dir.path().join("grammars/foo.wasm"), b"\0asm").unwrap();
+
+        let mut archive = Vec::new();
+        futures::executor::block_on(write_extension_archive(archive_dir.path(), &mut archive))
+            .unwrap();
+
+        let files = futures::executor::block_on(async {
+            let mut files = BTreeMap::default();
+            let mut entries = Archive::new(GzipDecoder::new(archive.as_slice()))
+                .entries()
+                .unwrap();
+            while let Some(entry) = entries.next().await {
+                let mut entry = entry.unwrap();
+                if entry.header().entry_type().is_file() {
+                    let path = entry.path().unwrap().to_string_lossy().into_owned();
+                    let mut content = Vec::new();
+                    entry.read_to_end(&mut content).await.unwrap();
+                    files.insert(path, content);
+                }
+            }
+            files
+        });
+        assert_eq!(
+            files,
+            BTreeMap::from_iter([
+                ("extension.toml".to_string(), b"id = \"foo\"\n".to_vec()),
+                ("grammars/foo.wasm".to_string(), b"\0asm".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_source_state() {
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec!["abc123\n", " M src/lib.rs\n", "def456\n", ""]),
+            ..Default::default()
+        });
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            PathBuf::from("/cache"),
+        );
+        builder.command_runner = runner.clone();
+
+        assert_eq!(
+            builder.source_state(Path::new("/extension")),
+            Some(SourceState {
+                commit: "abc123".into(),
+                dirty: true,
+            })
+        );
+        assert_eq!(
+            builder.source_state(Path::new("/extension")),
+            Some(SourceState {
+                commit: "def456".into(),
+                dirty: false,
+            })
+        );
+        assert_eq!(
+            runner.commands.lock().unwrap()[..2],
+            [
+                vec!["rev-parse", "HEAD"],
+                vec!["status", "--porcelain", "--", "."],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_theme_colors() {
+        let mut style = serde_json::json!({
+            "background": "#1E1E1E",
+            "border": "#abc",
+            "text": "#abcd",
+            "background.appearance": "opaque",
+            "accents": ["#112233", "#11223344"],
+            "syntax": {
+                "comment": { "color": "#808080", "font_style": "italic", "font_weight": 400 }
+            },
+            "custom": "not a color"
+        });
+        normalize_theme_colors(&mut style);
+        assert_eq!(
+            style,
+            serde_json::json!({
+                "background": "#1e1e1eff",
+                "border": "#aabbccff",
+                "text": "#aabbccdd",
+                "background.appearance": "opaque",
+                "accents": ["#112233ff", "#11223344"],
+                "syntax": {
+                    "comment": { "color": "#808080ff", "font_style": "italic", "font_weight": 400 }
+                },
+                "custom": "not a color"
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_theme_fields() {
+        assert_eq!(
+            missing_theme_fields(&serde_json::json!({
+                "name": "Family",
+                "author": "Someone",
+                "themes": [{ "name": "Dark", "appearance": "dark", "style": {} }]
+            })),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            missing_theme_fields(&serde_json::json!({ "name": "Family" })),
+            vec!["author", "themes"]
+        );
+        assert_eq!(
+            missing_theme_fields(&serde_json::json!({
+                "name": "Family",
+                "author": "Someone",
+                "themes": [
+                    { "name": "Dark", "appearance": "dark", "style": {} },
+                    { "appearance": 1, "style": [] }
+                ]
+            })),
+            vec!["themes[1].name", "themes[1].appearance", "themes[1].style"]
+        );
+    }
+
+    /// Writes an extension with a vendored grammar for each of `grammar_names` into
+    /// `extension_dir`, returning its manifest.
+    fn write_grammar_extension(
+        extension_dir: &Path,
+        extension_id: &str,
+        grammar_names: &[&str],
+    ) -> ExtensionManifest {
+        let mut manifest_content = format!(
+            "id = \"{extension_id}\"\nname = \"{extension_id}\"\nversion = \"1.0.0\"\n\
+            schema_version = 1\n"
+        );
+        for grammar_name in grammar_names {
+            manifest_content.push_str(&format!(
+                "[grammars.{grammar_name}]\n\
+                repository = \"https://github.com/tree-sitter/tree-sitter-{grammar_name}\"\n\
+                rev = \"main\"\n\
+                vendored_path = \"grammars/{grammar_name}\"\n"
+            ));
+            let src_dir = extension_dir
+                .join("grammars")
+                .join(grammar_name)
+                .join("src");
+            fs::create_dir_all(&src_dir).unwrap();
+            fs::write(src_dir.join("parser.c"), "#define LANGUAGE_VERSION 15\n").unwrap();
+        }
+        fs::write(extension_dir.join("extension.toml"), &manifest_content).unwrap();
+        toml::from_str(&manifest_content).unwrap()
+    }
+
+    /// Returns a builder that runs Clang with `runner`, which is given a Clang and sysroot so
+    /// that neither is downloaded.
+    fn fake_clang_builder(cache_dir: &Path, runner: Arc<FakeCommandRunner>) -> ExtensionBuilder {
+        let mut builder = ExtensionBuilder::new(
+            Arc::new(http_client::BlockedHttpClient::new()),
+            cache_dir.to_path_buf(),
+        );
+        builder.command_runner = runner;
+        builder.clang_path = Some(PathBuf::from("/wasi-sdk/bin/clang"));
+        builder.wasi_sysroot_path = Some(PathBuf::from("/wasi-sysroot"));
+        builder
+    }
+
+    #[test]
+    fn test_compile_extension_copy_source_to_cache() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let extension_dir = temp_dir.path().join("extension");
+        let mut manifest = write_grammar_extension(&extension_dir, "foo", &["foo"]);
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![""]),
+            writes_clang_outputs: true,
+            ..Default::default()
+        });
+        let cache_dir = temp_dir.path().join("cache");
+        let builder = fake_clang_builder(&cache_dir, runner.clone());
+
+        let output = futures::executor::block_on(builder.compile_extension(
+            &extension_dir,
+            &mut manifest,
+            CompileExtensionOptions {
+                copy_source_to_cache: true,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+
+        let build_dir = cache_dir.join("builds").join("foo");
+        assert_eq!(output.build_dir.as_ref(), Some(&build_dir));
+        assert!(build_dir.join("grammars").join("foo.wasm").is_file());
+        assert!(!extension_dir.join("grammars").join("foo.wasm").exists());
+        let parser_path = build_dir
+            .join("grammars")
+            .join("foo")
+            .join("src")
+            .join("parser.c");
+        let commands = runner.commands.lock().unwrap();
+        assert_eq!(commands.len(), 1);
+        assert!(
+            commands[0].contains(&parser_path.to_string_lossy().into_owned()),
+            "{:?}",
+            commands[0]
+        );
+    }
+
+    #[test]
+    fn test_compile_extension_sandbox_builds() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let extension_dir = temp_dir.path().join("extension");
+        let mut manifest = write_grammar_extension(&extension_dir, "foo", &["foo"]);
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![""]),
+            writes_clang_outputs: true,
+            ..Default::default()
+        });
+        let cache_dir = temp_dir.path().join("cache");
+        let mut builder = fake_clang_builder(&cache_dir, runner.clone());
+        builder.sandbox_builds = true;
+
+        let output = futures::executor::block_on(builder.compile_extension(
+            &extension_dir,
+            &mut manifest,
+            CompileExtensionOptions::default(),
+        ))
+        .unwrap();
+
+        let sandbox_dir = cache_dir.join("sandboxes").join("foo");
+        assert_eq!(output.artifacts_dir, Some(sandbox_dir.join("artifacts")));
+        assert!(
+            sandbox_dir
+                .join("artifacts")
+                .join("grammars")
+                .join("foo.wasm")
+                .is_file()
+        );
+        assert!(!sandbox_dir.join("source").exists());
+        assert!(!extension_dir.join("grammars").join("foo.wasm").exists());
+        assert_eq!(runner.commands.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compile_extensions_resumes_after_failure() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut extensions = ["alpha", "beta"].map(|extension_id| {
+            let extension_dir = temp_dir.path().join(extension_id);
+            BatchExtension {
+                manifest: write_grammar_extension(&extension_dir, extension_id, &[extension_id]),
+                extension_dir,
+            }
+        });
+        let batch_options = BatchBuildOptions {
+            state_path: Some(temp_dir.path().join("state.json")),
+            ..Default::default()
+        };
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec!["", ""]),
+            fails: Some(|args| {
+                args.iter()
+                    .any(|arg| arg == "-Wl,--export=tree_sitter_beta")
+            }),
+            writes_clang_outputs: true,
+            ..Default::default()
+        });
+        let mut builder = fake_clang_builder(&temp_dir.path().join("cache"), runner);
+
+        let output = futures::executor::block_on(builder.compile_extensions(
+            &mut extensions,
+            CompileExtensionOptions::default(),
+            batch_options.clone(),
+        ))
+        .unwrap();
+        assert_eq!(output.outputs.len(), 1);
+        assert!(output.outputs.contains_key("alpha"));
+        assert!(output.failures.contains_key("beta"));
+
+        // Running the batch again only builds the extension that failed.
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![""]),
+            writes_clang_outputs: true,
+            ..Default::default()
+        });
+        builder.command_runner = runner.clone();
+        let output = futures::executor::block_on(builder.compile_extensions(
+            &mut extensions,
+            CompileExtensionOptions::default(),
+            batch_options,
+        ))
+        .unwrap();
+        assert_eq!(output.skipped, [Arc::<str>::from("alpha")]);
+        assert_eq!(output.outputs.len(), 1);
+        assert!(output.outputs.contains_key("beta"));
+        assert!(output.failures.is_empty());
+        assert_eq!(runner.commands.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_compile_extension_combine_grammars() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let extension_dir = temp_dir.path().join("extension");
+        let mut manifest = write_grammar_extension(&extension_dir, "foo", &["alpha", "beta"]);
+        let runner = Arc::new(FakeCommandRunner {
+            stdouts: std::sync::Mutex::new(vec![""; 5]),
+            writes_clang_outputs: true,
+            ..Default::default()
+        });
+        let builder = fake_clang_builder(&temp_dir.path().join("cache"), runner.clone());
+
+        let output = futures::executor::block_on(builder.compile_extension(
+            &extension_dir,
+            &mut manifest,
+            CompileExtensionOptions {
+                combine_grammars: true,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+
+        assert_eq!(
+            output.combined_grammar_exports,
+            ["tree_sitter_alpha", "tree_sitter_beta"]
+        );
+        let combined_wasm = fs::read(extension_dir.join(COMBINED_GRAMMARS_WASM_FILE_NAME)).unwrap();
+        assert_eq!(
+            wasm_exports(&combined_wasm).unwrap(),
+            ["tree_sitter_alpha", "tree_sitter_beta"]
+        );
+        // Each grammar is compiled on its own, then to an object, and the objects are linked.
+        let commands = runner.commands.lock().unwrap();
+        assert_eq!(commands.len(), 5);
+        assert_eq!(
+            commands[4].iter().filter(|arg| arg.ends_with(".o")).count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,517 @@
+use super::grammar::verify_grammar_wasm_exports;
+use super::*;
+
+/// The maximum number of prebuilt grammar wasms to download at once.
+const MAX_CONCURRENT_GRAMMAR_DOWNLOADS: usize = 8;
+
+/// Compiling Tree-sitter parsers from C to WASM requires Clang 17, and a WASM build of libc
+/// and clang's runtime library. The `wasi-sdk` provides these binaries.
+///
+/// Once Clang 17 and its wasm target are available via system package managers, we won't need
+/// to download this.
+const WASI_SDK_URL: &str = "https://github.com/WebAssembly/wasi-sdk/releases/download/wasi-sdk-25/";
+
+const WASI_SYSROOT_ASSET_NAME: &str = "wasi-sysroot-25.0.tar.gz";
+
+const WASI_SDK_ASSET_NAME: Option<&str> = if cfg!(all(target_os = "macos", target_arch = "x86_64"))
+{
+    Some("wasi-sdk-25.0-x86_64-macos.tar.gz")
+} else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+    Some("wasi-sdk-25.0-arm64-macos.tar.gz")
+} else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+    Some("wasi-sdk-25.0-x86_64-linux.tar.gz")
+} else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+    Some("wasi-sdk-25.0-arm64-linux.tar.gz")
+} else if cfg!(all(target_os = "freebsd", target_arch = "x86_64")) {
+    Some("wasi-sdk-25.0-x86_64-linux.tar.gz")
+} else if cfg!(all(target_os = "freebsd", target_arch = "aarch64")) {
+    Some("wasi-sdk-25.0-arm64-linux.tar.gz")
+} else if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
+    Some("wasi-sdk-25.0-x86_64-windows.tar.gz")
+} else {
+    None
+};
+
+/// The release of the Tree-sitter CLI that is downloaded to generate grammar parsers when
+/// [`ExtensionBuilder::download_tree_sitter_cli`] is set.
+const TREE_SITTER_CLI_VERSION: &str = "0.25.3";
+
+const TREE_SITTER_CLI_URL: &str = "https://github.com/tree-sitter/tree-sitter/releases/download/";
+
+const TREE_SITTER_CLI_ASSET_NAME: Option<&str> =
+    if cfg!(all(target_os = "macos", target_arch = "x86_64")) {
+        Some("tree-sitter-macos-x64.gz")
+    } else if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+        Some("tree-sitter-macos-arm64.gz")
+    } else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        Some("tree-sitter-linux-x64.gz")
+    } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+        Some("tree-sitter-linux-arm64.gz")
+    } else if cfg!(all(target_os = "windows", target_arch = "x86_64")) {
+        Some("tree-sitter-windows-x64.gz")
+    } else {
+        None
+    };
+
+/// The file written into a downloaded WASI sysroot or wasi-sdk once it has been verified and
+/// unpacked.
+const VERIFIED_DOWNLOAD_MARKER: &str = ".verified";
+
+/// The SHA-256 checksums of the downloaded release assets, keyed by asset name, that downloads
+/// of [`WASI_SDK_ASSET_NAME`], [`WASI_SYSROOT_ASSET_NAME`] and [`TREE_SITTER_CLI_ASSET_NAME`] are
+/// verified against. The `wasi_sdk_sha256`, `wasi_sysroot_sha256` and `tree_sitter_cli_sha256`
+/// options of [`ExtensionBuilder`] override them.
+// TODO: Pin the checksums published with the wasi-sdk-25 and Tree-sitter 0.25.3 releases.
+const PINNED_ASSET_SHA256S: &[(&str, &str)] = &[];
+
+impl ExtensionBuilder {
+    /// Downloads the prebuilt wasm of each grammar that has a
+    /// [`GrammarManifestEntry::prebuilt_url`], running several downloads at once. Grammars with
+    /// the same URL share a single download.
+    pub(super) async fn download_prebuilt_grammars(
+        &self,
+        extension_dir: &Path,
+        manifest: &ExtensionManifest,
+        options: &CompileExtensionOptions,
+        compile_output: &mut CompileOutput,
+    ) -> Result<()> {
+        let mut grammar_names_by_url = BTreeMap::<&str, Vec<&Arc<str>>>::default();
+        for (grammar_name, grammar_metadata) in &manifest.grammars {
+            if let Some(url) = &grammar_metadata.prebuilt_url {
+                self.check_grammar_repository_allowed(url)
+                    .with_context(|| format!("prebuilt wasm of grammar '{grammar_name}'"))?;
+                grammar_names_by_url
+                    .entry(url.as_str())
+                    .or_default()
+                    .push(grammar_name);
+            }
+        }
+
+        let downloads = stream::iter(grammar_names_by_url)
+            .map(|(url, grammar_names)| async move {
+                log::info!("downloading prebuilt grammar wasm from {url}");
+                let mut response = self.http.get(url, AsyncBody::default(), true).await?;
+                if !response.status().is_success() {
+                    bail!("failed to download {url}: status {}", response.status());
+                }
+                let mut grammar_wasm = Vec::new();
+                response
+                    .body_mut()
+                    .read_to_end(&mut grammar_wasm)
+                    .await
+                    .with_context(|| format!("failed to download {url}"))?;
+                anyhow::Ok((grammar_names, grammar_wasm))
+            })
+            .buffer_unordered(MAX_CONCURRENT_GRAMMAR_DOWNLOADS)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        for (grammar_names, grammar_wasm) in downloads {
+            for grammar_name in grammar_names {
+                let grammar_wasm_path = grammar_wasm_path(extension_dir, grammar_name, options);
+                if let Some(parent) = grammar_wasm_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&grammar_wasm_path, &grammar_wasm)
+                    .with_context(|| format!("failed to write {}", grammar_wasm_path.display()))?;
+                verify_grammar_wasm_exports(&grammar_wasm_path, grammar_name)?;
+                self.record_grammar_wasm_size(
+                    compile_output,
+                    grammar_name,
+                    grammar_wasm.len() as u64,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the directory that a grammar's source archive is unpacked into. Archives are stored
+    /// by their hash, so each archive is only downloaded once.
+    pub(super) fn grammar_archive_dir(&self, archive: &GrammarArchive) -> PathBuf {
+        self.cache_dir
+            .join("grammar-archives")
+            .join(format!("{:x}", Sha256::digest(&archive.integrity)))
+    }
+
+    /// Downloads and unpacks a grammar's source archive, if it hasn't been already, after
+    /// verifying it against its integrity hash.
+    pub(super) async fn download_grammar_archive(&self, archive: &GrammarArchive) -> Result<()> {
+        self.check_grammar_repository_allowed(&archive.url)?;
+        let archive_dir = self.grammar_archive_dir(archive);
+        if archive_dir.is_dir() {
+            return Ok(());
+        }
+
+        let mut tar_out_dir = archive_dir.clone();
+        tar_out_dir.set_extension("archive");
+        remove_dir_if_exists(&tar_out_dir)?;
+
+        log::info!("downloading grammar source archive {}", archive.url);
+        let mut response = self
+            .http
+            .get(&archive.url, AsyncBody::default(), true)
+            .await?;
+        if !response.status().is_success() {
+            bail!(
+                "failed to download {}: status {}",
+                archive.url,
+                response.status()
+            );
+        }
+        let mut archive_bytes = Vec::new();
+        response
+            .body_mut()
+            .read_to_end(&mut archive_bytes)
+            .await
+            .with_context(|| format!("failed to download {}", archive.url))?;
+        verify_integrity(&archive_bytes, &archive.integrity).with_context(|| {
+            format!("source archive {} failed its integrity check", archive.url)
+        })?;
+
+        Archive::new(GzipDecoder::new(archive_bytes.as_slice()))
+            .unpack(&tar_out_dir)
+            .await
+            .context("failed to unpack grammar source archive")?;
+
+        // Archives of repositories, such as those from GitHub, usually contain a single top-level
+        // directory.
+        let entries = fs::read_dir(&tar_out_dir)?.collect::<io::Result<Vec<_>>>()?;
+        let root_dir = match entries.as_slice() {
+            [entry] if entry.file_type()?.is_dir() => entry.path(),
+            _ => tar_out_dir.clone(),
+        };
+        if let Some(parent) = archive_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&root_dir, &archive_dir)
+            .context("failed to move extracted grammar source archive")?;
+        remove_dir_if_exists(&tar_out_dir)?;
+
+        Ok(())
+    }
+
+    /// Returns the WASI sysroot to compile grammars against with a configured Clang: the
+    /// configured sysroot, then the one named by `WASI_LIBC_PATH`, then one found in a common
+    /// install location, and finally a pinned sysroot that is downloaded into the cache directory.
+    pub(super) async fn wasi_sysroot_dir(&self) -> Result<PathBuf> {
+        if let Some(wasi_sysroot_path) = &self.wasi_sysroot_path {
+            return Ok(wasi_sysroot_path.clone());
+        }
+        if let Some(wasi_libc_path) = env::var_os("WASI_LIBC_PATH") {
+            return Ok(PathBuf::from(wasi_libc_path));
+        }
+        if let Some(installed_sysroot_dir) = wasi_sysroot_candidates()
+            .into_iter()
+            .find(|candidate| candidate.join("include").is_dir())
+        {
+            log::info!(
+                "using WASI sysroot installed at {}",
+                installed_sysroot_dir.display()
+            );
+            return Ok(installed_sysroot_dir);
+        }
+
+        let url = format!("{WASI_SDK_URL}{WASI_SYSROOT_ASSET_NAME}");
+        let Some(expected_checksum) = self
+            .wasi_sysroot_sha256
+            .as_deref()
+            .or_else(|| pinned_asset_sha256(WASI_SYSROOT_ASSET_NAME))
+        else {
+            bail!(
+                "no checksum is pinned for {url}. Set `wasi_sysroot_sha256` to its SHA-256 \
+                checksum, or set `wasi_sysroot_path` to an installed sysroot."
+            );
+        };
+
+        // The cached sysroot is keyed on its version and checksum, so that neither updating the
+        // pinned version nor expecting a different checksum reuses an outdated sysroot. The
+        // marker is only written once the download has been verified and fully unpacked, so a
+        // sysroot that has it can be reused without checking it again.
+        let wasi_sysroot_dir = self.cached_wasi_sysroot_dir(expected_checksum);
+        let verified_marker_path = wasi_sysroot_dir.join(VERIFIED_DOWNLOAD_MARKER);
+        if verified_marker_path.is_file() {
+            return Ok(wasi_sysroot_dir);
+        }
+
+        let mut tar_out_dir = wasi_sysroot_dir.clone().into_os_string();
+        tar_out_dir.push(".archive");
+        let tar_out_dir = PathBuf::from(tar_out_dir);
+        for dir in [&wasi_sysroot_dir, &tar_out_dir] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)
+                    .with_context(|| format!("failed to remove {}", dir.display()))?;
+            }
+        }
+
+        log::info!("downloading WASI sysroot to {}", wasi_sysroot_dir.display());
+        let mut response = self.http.get(&url, AsyncBody::default(), true).await?;
+        if !response.status().is_success() {
+            bail!("failed to download {url}: status {}", response.status());
+        }
+        let mut archive = Vec::new();
+        response
+            .body_mut()
+            .read_to_end(&mut archive)
+            .await
+            .context("failed to download WASI sysroot")?;
+
+        let checksum = format!("{:x}", Sha256::digest(&archive));
+        if !checksum.eq_ignore_ascii_case(expected_checksum) {
+            bail!(
+                "WASI sysroot archive has checksum {checksum}, but {expected_checksum} was expected"
+            );
+        }
+
+        Archive::new(GzipDecoder::new(archive.as_slice()))
+            .unpack(&tar_out_dir)
+            .await
+            .context("failed to unpack WASI sysroot archive")?;
+
+        let inner_dir = fs::read_dir(&tar_out_dir)?
+            .next()
+            .context("no content")?
+            .context("failed to read contents of extracted WASI sysroot archive directory")?
+            .path();
+        fs::rename(&inner_dir, &wasi_sysroot_dir)
+            .context("failed to move extracted WASI sysroot dir")?;
+        fs::remove_dir_all(&tar_out_dir)
+            .with_context(|| format!("failed to remove {}", tar_out_dir.display()))?;
+        fs::write(&verified_marker_path, &checksum)
+            .with_context(|| format!("failed to write {}", verified_marker_path.display()))?;
+
+        Ok(wasi_sysroot_dir)
+    }
+
+    /// Returns the directory that the pinned WASI sysroot with the given checksum is downloaded
+    /// into.
+    pub(super) fn cached_wasi_sysroot_dir(&self, sha256: &str) -> PathBuf {
+        self.cache_dir.join("wasi-sysroot").join(format!(
+            "{}-{}",
+            WASI_SYSROOT_ASSET_NAME.trim_end_matches(".tar.gz"),
+            sha256.to_ascii_lowercase()
+        ))
+    }
+
+    /// Removes the WASI sysroots downloaded by earlier builds, so that the next build that needs
+    /// one downloads it again. This recovers from a cached sysroot that has been corrupted.
+    pub fn clear_cached_wasi_sysroot(&self) -> Result<()> {
+        let wasi_sysroots_dir = self.cache_dir.join("wasi-sysroot");
+        if wasi_sysroots_dir.exists() {
+            fs::remove_dir_all(&wasi_sysroots_dir)
+                .with_context(|| format!("failed to remove {}", wasi_sysroots_dir.display()))?;
+        }
+        Ok(())
+    }
+
+    pub(super) async fn install_wasi_sdk_if_needed(&self) -> Result<PathBuf> {
+        let Some(asset_name) = WASI_SDK_ASSET_NAME else {
+            bail!("wasi-sdk is not available for platform {}", env::consts::OS);
+        };
+        let url = format!("{WASI_SDK_URL}{asset_name}");
+
+        let wasi_sdk_dir = self.cache_dir.join("wasi-sdk");
+        let mut clang_path = wasi_sdk_dir.clone();
+        clang_path.extend(["bin", &format!("clang{}", env::consts::EXE_SUFFIX)]);
+        let verified_marker_path = wasi_sdk_dir.join(VERIFIED_DOWNLOAD_MARKER);
+
+        let Some(expected_checksum) = self
+            .wasi_sdk_sha256
+            .as_deref()
+            .or_else(|| pinned_asset_sha256(asset_name))
+        else {
+            bail!(
+                "no checksum is pinned for {url}. Set `wasi_sdk_sha256` to its SHA-256 \
+                checksum, or set `clang_path` to an installed Clang."
+            );
+        };
+
+        // A wasi-sdk without the marker, including one installed before downloads were verified,
+        // is downloaded again.
+        if fs::read_to_string(&verified_marker_path)
+            .is_ok_and(|checksum| checksum.trim().eq_ignore_ascii_case(expected_checksum))
+            && clang_path.is_file()
+        {
+            return Ok(clang_path);
+        }
+
+        let mut tar_out_dir = wasi_sdk_dir.clone();
+        tar_out_dir.set_extension("archive");
+
+        remove_dir_if_exists(&wasi_sdk_dir)?;
+        remove_dir_if_exists(&tar_out_dir)?;
+
+        log::info!("downloading wasi-sdk to {}", wasi_sdk_dir.display());
+        let mut response = self.http.get(&url, AsyncBody::default(), true).await?;
+        if !response.status().is_success() {
+            bail!("failed to download {url}: status {}", response.status());
+        }
+        let mut archive = Vec::new();
+        response
+            .body_mut()
+            .read_to_end(&mut archive)
+            .await
+            .context("failed to download wasi-sdk")?;
+
+        let checksum = format!("{:x}", Sha256::digest(&archive));
+        if !checksum.eq_ignore_ascii_case(expected_checksum) {
+            bail!("wasi-sdk archive has checksum {checksum}, but {expected_checksum} was expected");
+        }
+
+        Archive::new(GzipDecoder::new(archive.as_slice()))
+            .unpack(&tar_out_dir)
+            .await
+            .context("failed to unpack wasi-sdk archive")?;
+
+        let inner_dir = fs::read_dir(&tar_out_dir)?
+            .next()
+            .context("no content")?
+            .context("failed to read contents of extracted wasi archive directory")?
+            .path();
+        fs::rename(&inner_dir, &wasi_sdk_dir).context("failed to move extracted wasi dir")?;
+        remove_dir_if_exists(&tar_out_dir)?;
+        fs::write(&verified_marker_path, &checksum)
+            .with_context(|| format!("failed to write {}", verified_marker_path.display()))?;
+
+        Ok(clang_path)
+    }
+
+    /// Downloads the pinned release of the Tree-sitter CLI into the cache directory, if it hasn't
+    /// been already, and returns its path.
+    pub(super) async fn install_tree_sitter_cli_if_needed(&self) -> Result<PathBuf> {
+        let Some(asset_name) = TREE_SITTER_CLI_ASSET_NAME else {
+            bail!(
+                "the Tree-sitter CLI is not available for platform {}",
+                env::consts::OS
+            );
+        };
+        let url = format!("{TREE_SITTER_CLI_URL}v{TREE_SITTER_CLI_VERSION}/{asset_name}");
+
+        let tree_sitter_dir = self
+            .cache_dir
+            .join("tree-sitter-cli")
+            .join(TREE_SITTER_CLI_VERSION);
+        let tree_sitter_path =
+            tree_sitter_dir.join(format!("tree-sitter{}", env::consts::EXE_SUFFIX));
+        if tree_sitter_path.is_file() {
+            return Ok(tree_sitter_path);
+        }
+
+        let Some(expected_checksum) = self
+            .tree_sitter_cli_sha256
+            .as_deref()
+            .or_else(|| pinned_asset_sha256(asset_name))
+        else {
+            bail!(
+                "no checksum is pinned for {url}. Set `tree_sitter_cli_sha256` to its SHA-256 \
+                checksum."
+            );
+        };
+
+        log::info!(
+            "downloading Tree-sitter CLI to {}",
+            tree_sitter_dir.display()
+        );
+        let mut response = self.http.get(&url, AsyncBody::default(), true).await?;
+        if !response.status().is_success() {
+            bail!("failed to download {url}: status {}", response.status());
+        }
+        let mut compressed = Vec::new();
+        response
+            .body_mut()
+            .read_to_end(&mut compressed)
+            .await
+            .context("failed to download Tree-sitter CLI")?;
+
+        let checksum = format!("{:x}", Sha256::digest(&compressed));
+        if !checksum.eq_ignore_ascii_case(expected_checksum) {
+            bail!("Tree-sitter CLI has checksum {checksum}, but {expected_checksum} was expected");
+        }
+
+        let mut binary = Vec::new();
+        GzipDecoder::new(compressed.as_slice())
+            .read_to_end(&mut binary)
+            .await
+            .context("failed to decompress Tree-sitter CLI")?;
+
+        // Write to a temporary path first, so that an interrupted download isn't mistaken for an
+        // installed CLI.
+        fs::create_dir_all(&tree_sitter_dir)?;
+        let download_path = tree_sitter_path.with_extension("download");
+        fs::write(&download_path, &binary)
+            .with_context(|| format!("failed to write {}", download_path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            fs::set_permissions(&download_path, fs::Permissions::from_mode(0o755))?;
+        }
+        fs::rename(&download_path, &tree_sitter_path)
+            .context("failed to move downloaded Tree-sitter CLI")?;
+
+        Ok(tree_sitter_path)
+    }
+}
+
+/// Returns the pinned SHA-256 checksum of the release asset named `asset_name`, if there is one.
+fn pinned_asset_sha256(asset_name: &str) -> Option<&'static str> {
+    PINNED_ASSET_SHA256S
+        .iter()
+        .find(|(name, _)| *name == asset_name)
+        .map(|(_, sha256)| *sha256)
+}
+
+/// Returns the directories that a WASI sysroot is commonly installed in, in order of preference:
+/// the sysroot of the wasi-sdk named by `WASI_SDK_PATH`, those of wasi-sdk releases unpacked into
+/// `/opt`, and those installed by system package managers.
+fn wasi_sysroot_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(wasi_sdk_path) = env::var_os("WASI_SDK_PATH") {
+        candidates.push(Path::new(&wasi_sdk_path).join("share/wasi-sysroot"));
+    }
+
+    // wasi-sdk releases unpack into versioned directories, such as
+    // `/opt/wasi-sdk-25.0-x86_64-linux`, which are often symlinked to `/opt/wasi-sdk`.
+    let mut opt_wasi_sdk_dirs = fs::read_dir("/opt")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with("wasi-sdk"))
+        })
+        .collect::<Vec<_>>();
+    opt_wasi_sdk_dirs.sort();
+    candidates.extend(
+        opt_wasi_sdk_dirs
+            .into_iter()
+            .map(|wasi_sdk_dir| wasi_sdk_dir.join("share/wasi-sysroot")),
+    );
+
+    candidates.extend(
+        [
+            "/usr/share/wasi-sysroot",
+            "/usr/local/share/wasi-sysroot",
+            "/opt/homebrew/share/wasi-sysroot",
+        ]
+        .map(PathBuf::from),
+    );
+    candidates
+}
+
+/// Checks `bytes` against a [Subresource Integrity](https://www.w3.org/TR/SRI/) hash, such as
+/// `sha256-<base64 digest>`.
+pub(super) fn verify_integrity(bytes: &[u8], integrity: &str) -> Result<()> {
+    let (algorithm, expected_digest) = integrity
+        .split_once('-')
+        .with_context(|| format!("invalid integrity hash {integrity:?}"))?;
+    let digest = match algorithm {
+        "sha256" => BASE64_STANDARD.encode(Sha256::digest(bytes)),
+        "sha384" => BASE64_STANDARD.encode(Sha384::digest(bytes)),
+        "sha512" => BASE64_STANDARD.encode(Sha512::digest(bytes)),
+        _ => bail!("unsupported integrity hash algorithm {algorithm:?}"),
+    };
+    if digest != expected_digest {
+        bail!("expected {integrity}, but the content has hash {algorithm}-{digest}");
+    }
+    Ok(())
+}
@@ -0,0 +1,552 @@
+use super::*;
+
+impl ExtensionBuilder {
+    /// Fails if the grammar repository at `url` may not be fetched, according to
+    /// [`Self::allowed_grammar_repositories`] and [`Self::denied_grammar_repositories`]. The
+    /// manifest's URL is checked, rather than that of any mirror it is fetched from.
+    ///
+    /// This applies to every URL that grammar sources or wasms are downloaded from, including
+    /// source archives and prebuilt grammar wasms.
+    pub(super) fn check_grammar_repository_allowed(&self, url: &str) -> Result<()> {
+        if self.allowed_grammar_repositories.is_empty()
+            && self.denied_grammar_repositories.is_empty()
+        {
+            return Ok(());
+        }
+        // URLs that can't be parsed, such as SCP-style `git@host:path` URLs, can't be matched
+        // reliably, so they are rejected.
+        let parsed_url = Url::parse(url)
+            .with_context(|| format!("grammar repository {url} is not a valid URL"))?;
+        if let Some(denied_rule) =
+            find_grammar_repository_rule(&self.denied_grammar_repositories, &parsed_url)?
+        {
+            bail!("grammar repository {url} is denied by the rule `{denied_rule}`");
+        }
+        if !self.allowed_grammar_repositories.is_empty()
+            && find_grammar_repository_rule(&self.allowed_grammar_repositories, &parsed_url)?
+                .is_none()
+        {
+            bail!(
+                "grammar repository {url} is not allowed. Allowed repositories must be within \
+                one of: {}",
+                self.allowed_grammar_repositories.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    pub(super) fn checkout_repo(&self, directory: &Path, url: &str, rev: &str) -> Result<()> {
+        self.check_grammar_repository_allowed(url)?;
+        let git_dir = directory.join(".git");
+
+        if directory.exists() {
+            let remotes_output = self.command_runner.output(
+                self.command("git")?
+                    .arg("--git-dir")
+                    .arg(&git_dir)
+                    .args(["remote", "-v"]),
+            )?;
+            let has_remote = remotes_output.status.success()
+                && String::from_utf8_lossy(&remotes_output.stdout)
+                    .lines()
+                    .any(|line| {
+                        let mut parts = line.split(|c: char| c.is_whitespace());
+                        parts.next() == Some("origin") && parts.any(|part| part == url)
+                    });
+            if !has_remote {
+                bail!(
+                    "grammar directory '{}' already exists, but is not a git clone of '{}'. \
+                    Remove it, or enable `namespace_grammar_checkouts` to check out grammars \
+                    into directories specific to their repository.",
+                    directory.display(),
+                    url
+                );
+            }
+        } else {
+            fs::create_dir_all(directory).with_context(|| {
+                format!("failed to create grammar directory {}", directory.display(),)
+            })?;
+            let init_output = self
+                .command_runner
+                .output(self.command("git")?.arg("init").current_dir(directory))?;
+            if !init_output.status.success() {
+                bail!(
+                    "failed to run `git init` in directory '{}'",
+                    directory.display()
+                );
+            }
+
+            let remote_add_output = self
+                .command_runner
+                .output(
+                    self.command("git")?
+                        .arg("--git-dir")
+                        .arg(&git_dir)
+                        .args(["remote", "add", "origin", url]),
+                )
+                .context("failed to execute `git remote add`")?;
+            if !remote_add_output.status.success() {
+                bail!(
+                    "failed to add remote {url} for git repository {}",
+                    git_dir.display()
+                );
+            }
+        }
+
+        let mut fetch_command = self.git_fetch_origin_command(&git_dir, url)?;
+        if let Some(depth) = self.git_fetch_depth {
+            fetch_command.arg("--depth").arg(depth.to_string());
+        }
+        let mut fetch_output = self
+            .command_runner
+            .output(fetch_command.args(["origin", rev]))
+            .context("failed to execute `git fetch`")?;
+        if !fetch_output.status.success() && self.git_fetch_depth.is_some() {
+            // Some servers don't allow fetching arbitrary commits, so fall back to fetching
+            // all of the remote's branches and tags, one of which should contain the revision.
+            log::debug!(
+                "shallow fetch of revision {rev} from {url} failed, falling back to a full fetch"
+            );
+            let mut full_fetch_command = self.git_fetch_origin_command(&git_dir, url)?;
+            if git_dir.join("shallow").exists() {
+                full_fetch_command.arg("--unshallow");
+            }
+            fetch_output = self
+                .command_runner
+                .output(full_fetch_command.args([
+                    "--tags",
+                    "origin",
+                    "+refs/heads/*:refs/remotes/origin/*",
+                ]))
+                .context("failed to execute `git fetch`")?;
+        }
+
+        let checkout_output = self
+            .command_runner
+            .output(
+                self.command("git")?
+                    .arg("--git-dir")
+                    .arg(&git_dir)
+                    .args(["checkout", rev])
+                    .current_dir(directory),
+            )
+            .context("failed to execute `git checkout`")?;
+        if !checkout_output.status.success() {
+            if !fetch_output.status.success() {
+                bail!(
+                    "failed to fetch revision {} in directory '{}'",
+                    rev,
+                    directory.display()
+                );
+            }
+            bail!(
+                "failed to checkout revision {} in directory '{}': {}",
+                rev,
+                directory.display(),
+                String::from_utf8_lossy(&checkout_output.stderr)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the Git LFS pointers in a checked out repository with the files' content.
+    pub(super) fn pull_lfs_files(&self, directory: &Path, url: &str) -> Result<()> {
+        let version_output = self
+            .command_runner
+            .output(self.command("git")?.args(["lfs", "version"]))
+            .context("failed to execute `git lfs version`")?;
+        if !version_output.status.success() {
+            bail!(
+                "grammar repository {url} stores files with Git LFS, but `git-lfs` is not installed. \
+                Install it from https://git-lfs.com and run `git lfs install`."
+            );
+        }
+
+        log::info!("pulling Git LFS files in {}", directory.display());
+        let pull_output = self
+            .command_runner
+            .output(
+                self.git_origin_command(url)?
+                    .args(["lfs", "pull"])
+                    .current_dir(directory),
+            )
+            .context("failed to execute `git lfs pull`")?;
+        if !pull_output.status.success() {
+            bail!(
+                "failed to pull Git LFS files in directory '{}': {}",
+                directory.display(),
+                String::from_utf8_lossy(&pull_output.stderr)
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the URL to fetch the repository at `url` from, after applying the longest
+    /// matching [`GitUrlRewrite`].
+    fn git_fetch_url(&self, url: &str) -> String {
+        self.git_url_rewrite(url)
+            .map(|rewrite| format!("{}{}", rewrite.to_prefix, &url[rewrite.from_prefix.len()..]))
+            .unwrap_or_else(|| url.to_string())
+    }
+
+    /// Returns the rule in [`Self::git_url_rewrites`] that applies to `url`, if any.
+    fn git_url_rewrite(&self, url: &str) -> Option<&GitUrlRewrite> {
+        self.git_url_rewrites
+            .iter()
+            .filter(|rewrite| url.starts_with(&rewrite.from_prefix))
+            .max_by_key(|rewrite| rewrite.from_prefix.len())
+    }
+
+    /// Returns a `git fetch` command for the `origin` remote of the repository at `git_dir`,
+    /// whose URL is `url`. Fetching through the remote, rather than from its URL, keeps the
+    /// remote-tracking refs that branch and tag revisions are checked out from up to date.
+    ///
+    /// When `url` is fetched from a mirror, the mirror is applied with `url.<mirror>.insteadOf`
+    /// for this command alone, so `origin` keeps the manifest's URL.
+    fn git_fetch_origin_command(&self, git_dir: &Path, url: &str) -> Result<Command> {
+        let mut command = self.git_origin_command(url)?;
+        command.arg("--git-dir").arg(git_dir).arg("fetch");
+        Ok(command)
+    }
+
+    /// Returns a `git` command for talking to an `origin` remote whose URL is `url`, which
+    /// authenticates with the matching credential and fetches from the matching mirror, if any.
+    fn git_origin_command(&self, url: &str) -> Result<Command> {
+        let fetch_url = self.git_fetch_url(url);
+        let mut command = self.git_command_for_url(&fetch_url)?;
+        if let Some(rewrite) = self.git_url_rewrite(url) {
+            log::debug!("fetching {url} from mirror {fetch_url}");
+            command.arg("-c").arg(format!(
+                "url.{}.insteadOf={}",
+                rewrite.to_prefix, rewrite.from_prefix
+            ));
+        }
+        Ok(command)
+    }
+
+    fn git_fetch_command(&self, git_dir: &Path, url: &str) -> Result<Command> {
+        let mut command = self.git_command_for_url(url)?;
+        command.arg("--git-dir").arg(git_dir).arg("fetch");
+        Ok(command)
+    }
+
+    /// Returns a `git` command that authenticates with the credential matching `url`, if any.
+    pub(super) fn git_command_for_url(&self, url: &str) -> Result<Command> {
+        let mut command = self.command("git")?;
+        if let Some(credential) = self
+            .git_credentials
+            .iter()
+            .filter(|credential| url.starts_with(&credential.url_prefix))
+            .max_by_key(|credential| credential.url_prefix.len())
+        {
+            let is_github = Url::parse(url).is_ok_and(|url| {
+                url.host_str()
+                    .is_some_and(|host| host.eq_ignore_ascii_case("github.com"))
+            });
+            let username = match &credential.username {
+                Some(username) => username.as_str(),
+                None if is_github => "x-access-token",
+                None => bail!(
+                    "the credential for {} must set a username to authenticate with {url}",
+                    credential.url_prefix
+                ),
+            };
+            // Pass the token through the environment rather than the URL, so that it isn't
+            // persisted in the repository's config or visible in the process list.
+            let authorization = BASE64_STANDARD.encode(format!("{username}:{}", credential.token));
+            let inherited_config_count = if self.sandbox_builds {
+                None
+            } else {
+                env::var_os("GIT_CONFIG_COUNT")
+            };
+            add_git_config_env(
+                &mut command,
+                inherited_config_count,
+                &format!("http.{url}.extraHeader"),
+                &format!("Authorization: Basic {authorization}"),
+            )?;
+        }
+        Ok(command)
+    }
+
+    /// Resolves the revision of each of the manifest's grammars to a commit SHA, by querying the
+    /// grammar's remote repository with `git ls-remote` rather than checking it out.
+    ///
+    /// Grammars that are vendored or in local repositories are compiled as-is, and so are
+    /// omitted.
+    pub fn resolve_grammar_revs(
+        &self,
+        manifest: &ExtensionManifest,
+    ) -> Result<BTreeMap<Arc<str>, String>> {
+        let mut resolved_revs = BTreeMap::default();
+        for (grammar_name, grammar_metadata) in &manifest.grammars {
+            if grammar_metadata.vendored_path.is_some()
+                || grammar_metadata.archive.is_some()
+                || local_grammar_repository_dir(Path::new(""), &grammar_metadata.repository)
+                    .is_some()
+            {
+                continue;
+            }
+            let rev = grammar_rev(grammar_name, grammar_metadata)?;
+            if rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()) {
+                resolved_revs.insert(grammar_name.clone(), rev.to_lowercase());
+                continue;
+            }
+
+            self.check_grammar_repository_allowed(&grammar_metadata.repository)?;
+            let url = self.git_fetch_url(&grammar_metadata.repository);
+            let output = self
+                .command_runner
+                .output(
+                    self.git_command_for_url(&url)?
+                        .arg("ls-remote")
+                        .arg(&url)
+                        .arg(rev)
+                        .arg(format!("{rev}^{{}}")),
+                )
+                .context("failed to execute `git ls-remote`")?;
+            if !output.status.success() {
+                bail!(
+                    "failed to list remote refs of {url} for grammar '{grammar_name}': {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+
+            let refs = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .map(|(sha, name)| (sha.to_string(), name.to_string()))
+                .collect::<Vec<_>>();
+            let sha = remote_ref_sha(&refs, rev)
+                .with_context(|| format!("failed to resolve grammar '{grammar_name}' in {url}"))?;
+            resolved_revs.insert(grammar_name.clone(), sha);
+        }
+        Ok(resolved_revs)
+    }
+
+    /// Checks that the revision of each of the manifest's grammars exists in the grammar's
+    /// repository, without checking the grammars out. This catches a mistyped revision before a
+    /// long build, rather than when the grammar is compiled.
+    ///
+    /// Branches and tags are looked up with `git ls-remote`. Commit SHAs can't be listed, so the
+    /// commit alone is fetched into a scratch repository in the cache directory, which fails
+    /// for repositories on servers that don't allow fetching arbitrary commits.
+    pub fn preflight_grammar_revs(&self, manifest: &ExtensionManifest) -> Result<()> {
+        let resolved_revs = self.resolve_grammar_revs(manifest)?;
+
+        let preflight_git_dir = self.cache_dir.join("grammar-rev-preflight.git");
+        for (grammar_name, grammar_metadata) in &manifest.grammars {
+            let Some(sha) = resolved_revs.get(grammar_name) else {
+                continue;
+            };
+            if !grammar_rev(grammar_name, grammar_metadata)?.eq_ignore_ascii_case(sha) {
+                continue;
+            }
+
+            if !preflight_git_dir.join("HEAD").exists() {
+                let init_output = self.command_runner.output(
+                    self.command("git")?
+                        .args(["init", "--bare"])
+                        .arg(&preflight_git_dir),
+                )?;
+                if !init_output.status.success() {
+                    bail!(
+                        "failed to create git repository {}: {}",
+                        preflight_git_dir.display(),
+                        String::from_utf8_lossy(&init_output.stderr)
+                    );
+                }
+            }
+
+            let url = self.git_fetch_url(&grammar_metadata.repository);
+            let fetch_output = self
+                .command_runner
+                .output(
+                    self.git_fetch_command(&preflight_git_dir, &url)?
+                        .args(["--depth=1", "--filter=tree:0"])
+                        .args([url.as_str(), sha.as_str()]),
+                )
+                .context("failed to execute `git fetch`")?;
+            if !fetch_output.status.success() {
+                bail!(
+                    "revision {sha} of grammar '{grammar_name}' was not found in {url}: {}",
+                    String::from_utf8_lossy(&fetch_output.stderr)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the Git commit checked out in `dir` and whether `dir` has uncommitted changes, if
+    /// it is in a Git repository.
+    pub(super) fn source_state(&self, dir: &Path) -> Option<SourceState> {
+        let output = self
+            .command_runner
+            .output(
+                self.command("git")
+                    .ok()?
+                    .args(["rev-parse", "HEAD"])
+                    .current_dir(dir),
+            )
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let commit = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        // Only changes within the extension's directory count, since it may be part of a larger
+        // repository.
+        let output = self
+            .command_runner
+            .output(
+                self.command("git")
+                    .ok()?
+                    .args(["status", "--porcelain", "--", "."])
+                    .current_dir(dir),
+            )
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        Some(SourceState {
+            commit,
+            dirty: !output.stdout.is_empty(),
+        })
+    }
+
+    /// Returns the commit time of the Git commit checked out in `dir`, in seconds since the Unix
+    /// epoch.
+    pub(super) fn git_commit_timestamp(&self, dir: &Path) -> Result<u64> {
+        let output = self
+            .command_runner
+            .output(
+                self.command("git")?
+                    .args(["log", "-1", "--format=%ct"])
+                    .current_dir(dir),
+            )
+            .context("failed to run `git log`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to read the commit time of {}: {}",
+                dir.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .context("invalid commit time")
+    }
+}
+
+/// Returns the revision of a grammar's repository to check out, trimmed of any surrounding
+/// whitespace, rejecting empty revisions that `git` would otherwise fail on confusingly.
+pub(super) fn grammar_rev<'a>(
+    grammar_name: &str,
+    grammar_metadata: &'a GrammarManifestEntry,
+) -> Result<&'a str> {
+    let rev = grammar_metadata.rev.trim();
+    if rev.is_empty() {
+        bail!("grammar {grammar_name} has an empty revision");
+    }
+    Ok(rev)
+}
+
+/// Returns the first of `rules` that the grammar repository at `url` is within: one with the
+/// same scheme, host and port, whose path is a prefix of the repository's path ending at a `/`.
+fn find_grammar_repository_rule<'a>(rules: &'a [String], url: &Url) -> Result<Option<&'a str>> {
+    for rule in rules {
+        let rule_url = Url::parse(rule)
+            .with_context(|| format!("invalid grammar repository rule `{rule}`"))?;
+        let hosts_match = match (rule_url.host_str(), url.host_str()) {
+            (Some(rule_host), Some(host)) => rule_host.eq_ignore_ascii_case(host),
+            (None, None) => true,
+            _ => false,
+        };
+        if rule_url.scheme() != url.scheme()
+            || !hosts_match
+            || rule_url.port_or_known_default() != url.port_or_known_default()
+        {
+            continue;
+        }
+        let rule_path = rule_url.path();
+        let path_matches = if rule_path.ends_with('/') {
+            url.path().starts_with(rule_path)
+        } else {
+            url.path()
+                .strip_prefix(rule_path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        };
+        if path_matches {
+            return Ok(Some(rule));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolves a grammar repository given as a relative path, such as `../tree-sitter-foo`, against
+/// the extension directory. Returns `None` for URLs, which need to be checked out with git.
+pub(super) fn local_grammar_repository_dir(
+    extension_dir: &Path,
+    repository: &str,
+) -> Option<PathBuf> {
+    // Anything with a colon is either a URL, an SCP-style git remote (`git@host:path`), or a
+    // Windows drive path, none of which are relative paths.
+    if repository.contains(':') || !Path::new(repository).is_relative() {
+        return None;
+    }
+    Some(extension_dir.join(repository))
+}
+
+/// Passes a config entry to the Git `command` through `GIT_CONFIG_COUNT`, `GIT_CONFIG_KEY_<n>` and
+/// `GIT_CONFIG_VALUE_<n>`, after any entries already set on the command or, failing that, in the
+/// `inherited_count` of the environment it inherits.
+pub(super) fn add_git_config_env(
+    command: &mut Command,
+    inherited_count: Option<OsString>,
+    key: &str,
+    value: &str,
+) -> Result<()> {
+    let count = match command
+        .get_envs()
+        .find(|(name, _)| *name == "GIT_CONFIG_COUNT")
+    {
+        Some((_, count)) => count.map(OsStr::to_os_string),
+        None => inherited_count,
+    };
+    let index = match count {
+        Some(count) => count
+            .to_str()
+            .and_then(|count| count.trim().parse::<usize>().ok())
+            .with_context(|| format!("invalid GIT_CONFIG_COUNT {count:?}"))?,
+        None => 0,
+    };
+    command
+        .env("GIT_CONFIG_COUNT", (index + 1).to_string())
+        .env(format!("GIT_CONFIG_KEY_{index}"), key)
+        .env(format!("GIT_CONFIG_VALUE_{index}"), value);
+    Ok(())
+}
+
+/// Returns the commit that `rev` names among the `(sha, ref name)` pairs listed by
+/// `git ls-remote`. Only a tag or branch named exactly `rev` matches, preferring the commit that
+/// an annotated tag points to over the tag object itself.
+fn remote_ref_sha(refs: &[(String, String)], rev: &str) -> Result<String> {
+    let find_ref = |name: &str| {
+        refs.iter()
+            .find(|(_, ref_name)| ref_name == name)
+            .map(|(sha, _)| sha.clone())
+    };
+    let tag_sha = find_ref(&format!("refs/tags/{rev}^{{}}"))
+        .or_else(|| find_ref(&format!("refs/tags/{rev}")));
+    let branch_sha = find_ref(&format!("refs/heads/{rev}"));
+    match (tag_sha, branch_sha) {
+        (Some(tag_sha), Some(branch_sha)) if tag_sha != branch_sha => {
+            bail!("revision {rev} is ambiguous, as both a tag and a branch are named {rev}")
+        }
+        (Some(sha), _) | (None, Some(sha)) => Ok(sha),
+        (None, None) => bail!("revision {rev} was not found"),
+    }
+}
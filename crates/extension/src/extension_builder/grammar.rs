@@ -0,0 +1,1198 @@
+use super::git::{grammar_rev, local_grammar_repository_dir};
+use super::*;
+
+/// The oldest Tree-sitter ABI version that we recommend grammars target. Older grammars may still
+/// load, but miss out on features like supertypes and reserved words.
+const MIN_RECOMMENDED_GRAMMAR_ABI_VERSION: u32 = 14;
+
+/// The start of a Git LFS pointer file, which is checked out in place of a file's content when
+/// the content hasn't been pulled from LFS.
+const GIT_LFS_POINTER_HEADER: &[u8] = b"version https://git-lfs.github.com/spec/";
+
+/// The file, next to a grammar's `parser.c`, recording the SHA-256 of the `grammar.json` that the
+/// parser was generated from.
+const GENERATED_PARSER_GRAMMAR_HASH_FILE: &str = "grammar.json.sha256";
+
+/// The source files and include directories that a grammar was compiled from, the C standard it
+/// was compiled with if it overrides the global one, and the symbols it exports besides
+/// `tree_sitter_<name>`.
+pub(super) struct GrammarSources {
+    include_dirs: Vec<PathBuf>,
+    source_files: Vec<PathBuf>,
+    c_standard: Option<String>,
+    extra_exports: Vec<String>,
+}
+
+impl ExtensionBuilder {
+    pub(super) async fn compile_grammar(
+        &self,
+        extension_dir: &Path,
+        source_dir: &Path,
+        grammar_name: &str,
+        grammar_metadata: &GrammarManifestEntry,
+        options: &CompileExtensionOptions,
+        compile_output: &mut CompileOutput,
+    ) -> Result<Option<GrammarSources>> {
+        let grammar_wasm_path = grammar_wasm_path(extension_dir, grammar_name, options);
+
+        if options.skip_committed_grammars && grammar_wasm_path.exists() {
+            log::debug!("using committed {grammar_name} parser");
+            verify_grammar_wasm_exports(&grammar_wasm_path, grammar_name)?;
+            self.record_grammar_wasm_size(
+                compile_output,
+                grammar_name,
+                fs::metadata(&grammar_wasm_path)?.len(),
+            )?;
+            return Ok(None);
+        }
+
+        let mut clang_command = self
+            .grammar_clang_command(options, compile_output.source_date_epoch)
+            .await?;
+
+        if let Some(archive) = &grammar_metadata.archive {
+            self.download_grammar_archive(archive)
+                .await
+                .with_context(|| {
+                    format!("failed to download source archive of grammar '{grammar_name}'")
+                })?;
+        }
+
+        let (repository_dir, base_grammar_path) =
+            self.grammar_source_dir(extension_dir, source_dir, grammar_name, grammar_metadata)?;
+
+        let src_path = grammar_src_dir(&base_grammar_path);
+        let parser_path = src_path.join("parser.c");
+        let scanner_path = src_path.join("scanner.c");
+
+        // Large generated parsers are sometimes stored with Git LFS, in which case a checkout
+        // only contains pointers to them.
+        for path in [&parser_path, &scanner_path, &src_path.join("grammar.json")] {
+            if is_lfs_pointer(path)? {
+                // Only checkouts have an LFS remote to pull from; vendored, archived and local
+                // sources would otherwise pull from whatever repository they happen to be in.
+                let is_checkout = grammar_metadata.vendored_path.is_none()
+                    && grammar_metadata.archive.is_none()
+                    && local_grammar_repository_dir(source_dir, &grammar_metadata.repository)
+                        .is_none();
+                if !is_checkout {
+                    bail!(
+                        "LFS pointer found in non-git source of grammar '{grammar_name}': {}",
+                        path.display()
+                    );
+                }
+                self.pull_lfs_files(&repository_dir, &grammar_metadata.repository)?;
+                break;
+            }
+        }
+
+        self.generate_grammar_parser_if_needed(grammar_name, &base_grammar_path)
+            .await?;
+
+        if let Some(abi_version) = grammar_abi_version(&parser_path)? {
+            compile_output
+                .grammar_abi_versions
+                .insert(grammar_name.into(), abi_version);
+            if abi_version < MIN_RECOMMENDED_GRAMMAR_ABI_VERSION {
+                let warning = format!(
+                    "grammar '{grammar_name}' was generated for Tree-sitter ABI version {abi_version}, \
+                    but version {MIN_RECOMMENDED_GRAMMAR_ABI_VERSION} or newer is recommended. \
+                    Regenerate it by running `tree-sitter generate` with a newer Tree-sitter CLI."
+                );
+                log::warn!("{warning}");
+                compile_output
+                    .grammar_warnings
+                    .entry(grammar_name.into())
+                    .or_default()
+                    .push(warning);
+            }
+        }
+
+        // Scanners may include headers shared between several grammars in the same repository,
+        // outside of the grammar's `src` directory.
+        let mut scanner_include_dirs = Vec::new();
+        if scanner_path.exists() {
+            scanner_include_dirs.push(repository_dir.clone());
+            scanner_include_dirs.extend(
+                options
+                    .grammar_include_dirs
+                    .iter()
+                    .map(|include_dir| repository_dir.join(include_dir)),
+            );
+        }
+
+        let mut include_dirs = vec![src_path];
+        include_dirs.extend(scanner_include_dirs);
+        let mut source_files = vec![parser_path];
+        source_files.extend(scanner_path.exists().then_some(scanner_path));
+        let grammar_sources = GrammarSources {
+            include_dirs,
+            source_files,
+            c_standard: grammar_metadata.c_standard.clone(),
+            extra_exports: grammar_metadata.extra_exports.clone(),
+        };
+
+        // Clang uses the last `-std` it is given, so this overrides the global standard.
+        if let Some(c_standard) = &grammar_sources.c_standard {
+            clang_command.arg(format!("-std={c_standard}"));
+        }
+        clang_command.arg(format!("-Wl,--export=tree_sitter_{grammar_name}"));
+        clang_command.args(
+            grammar_sources
+                .extra_exports
+                .iter()
+                .map(|export_name| format!("-Wl,--export={export_name}")),
+        );
+
+        let artifact_cache_path = if options.cache_grammar_artifacts {
+            let cache_key = grammar_artifact_cache_key(&clang_command, &grammar_sources)?;
+            Some(
+                self.cache_dir
+                    .join("grammar-artifacts")
+                    .join(format!("{cache_key}.wasm")),
+            )
+        } else {
+            None
+        };
+        if let Some(artifact_cache_path) = &artifact_cache_path {
+            if artifact_cache_path.is_file() {
+                log::debug!("using cached build of {grammar_name} parser");
+                fs::copy(artifact_cache_path, &grammar_wasm_path)
+                    .context("failed to copy cached grammar wasm")?;
+                verify_grammar_wasm_exports(&grammar_wasm_path, grammar_name)?;
+                compile_output.cached_grammars.push(grammar_name.into());
+                self.record_grammar_wasm_size(
+                    compile_output,
+                    grammar_name,
+                    fs::metadata(&grammar_wasm_path)?.len(),
+                )?;
+                return Ok(Some(grammar_sources));
+            }
+        }
+
+        log::debug!("compiling {grammar_name} parser");
+        let clang_output = if options.cache_grammar_objects {
+            self.compile_grammar_with_object_cache(
+                &mut clang_command,
+                &grammar_sources,
+                &grammar_wasm_path,
+            )?
+        } else {
+            self.run_clang(
+                clang_command
+                    .arg("-o")
+                    .arg(&grammar_wasm_path)
+                    .args(
+                        grammar_sources
+                            .include_dirs
+                            .iter()
+                            .flat_map(|include_dir| [OsStr::new("-I"), include_dir.as_os_str()]),
+                    )
+                    .args(&grammar_sources.source_files),
+            )
+            .context("failed to run clang")?
+        };
+
+        let clang_stderr = String::from_utf8_lossy(&clang_output.stderr);
+        if !clang_output.status.success() {
+            return Err(ClangBuildError {
+                grammar_name: grammar_name.into(),
+                diagnostics: parse_clang_diagnostics(&clang_stderr),
+                stderr: clang_stderr.into_owned(),
+            }
+            .into());
+        }
+        record_grammar_clang_output(grammar_name, &clang_stderr, options, compile_output)?;
+
+        verify_grammar_wasm_exports(&grammar_wasm_path, grammar_name)?;
+        let unsupported_imports = unsupported_grammar_imports(
+            &fs::read(&grammar_wasm_path)?,
+            &self.allowed_grammar_imports,
+        )?;
+        if !unsupported_imports.is_empty() {
+            let warning = format!(
+                "grammar '{grammar_name}' imports functions that Tree-sitter doesn't provide, \
+                so it will fail to load: {}. These are likely C library functions called by its \
+                scanner.",
+                unsupported_imports.join(", ")
+            );
+            if options.deny_grammar_warnings {
+                bail!("{warning}");
+            }
+            log::warn!("{warning}");
+            compile_output
+                .grammar_warnings
+                .entry(grammar_name.into())
+                .or_default()
+                .push(warning);
+        }
+        self.record_grammar_wasm_size(
+            compile_output,
+            grammar_name,
+            fs::metadata(&grammar_wasm_path)?.len(),
+        )?;
+
+        if let Some(artifact_cache_path) = &artifact_cache_path {
+            if let Some(parent) = artifact_cache_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(&grammar_wasm_path, artifact_cache_path)
+                .context("failed to cache compiled grammar")?;
+        }
+
+        Ok(Some(grammar_sources))
+    }
+
+    /// Records the size of a grammar's wasm, failing if it exceeds [`Self::max_grammar_wasm_size`].
+    pub(super) fn record_grammar_wasm_size(
+        &self,
+        compile_output: &mut CompileOutput,
+        grammar_name: &str,
+        size: u64,
+    ) -> Result<()> {
+        if let Some(max_grammar_wasm_size) = self.max_grammar_wasm_size {
+            if size > max_grammar_wasm_size {
+                bail!(
+                    "grammar {grammar_name} is {size} bytes, which exceeds the limit of \
+                    {max_grammar_wasm_size} bytes"
+                );
+            }
+        }
+        compile_output
+            .grammar_wasm_sizes
+            .insert(grammar_name.into(), size);
+        Ok(())
+    }
+
+    /// Links the parsers and scanners of all of the extension's grammars into a single wasm,
+    /// which exports the `tree_sitter_<name>` function of each grammar.
+    ///
+    /// Each grammar's sources are first compiled to objects on their own, with the grammar's
+    /// include directories and C standard, so that grammars can't pick up each other's headers.
+    pub(super) async fn compile_combined_grammar_wasm(
+        &self,
+        extension_dir: &Path,
+        all_grammar_sources: &[(Arc<str>, Option<GrammarSources>)],
+        options: &CompileExtensionOptions,
+        compile_output: &mut CompileOutput,
+    ) -> Result<()> {
+        let combined_wasm_path = combined_grammar_wasm_path(extension_dir, options);
+
+        // Without the object cache, the objects are only kept until the next combined build.
+        let object_dir = if options.cache_grammar_objects {
+            self.cache_dir.join("grammar-objects")
+        } else {
+            let object_dir = self.cache_dir.join("combined-grammar-objects");
+            if object_dir.exists() {
+                fs::remove_dir_all(&object_dir)
+                    .with_context(|| format!("failed to remove {}", object_dir.display()))?;
+            }
+            object_dir
+        };
+        fs::create_dir_all(&object_dir)
+            .with_context(|| format!("failed to create {}", object_dir.display()))?;
+
+        let mut command = self
+            .grammar_clang_command(options, compile_output.source_date_epoch)
+            .await?;
+        command.arg("-Qunused-arguments");
+        command.arg("-o").arg(&combined_wasm_path);
+        let mut export_names = Vec::new();
+        let mut object_grammars = Vec::new();
+        for (grammar_name, grammar_sources) in all_grammar_sources {
+            let Some(grammar_sources) = grammar_sources else {
+                bail!(
+                    "grammar '{grammar_name}' uses a committed or prebuilt wasm, \
+                    so it can't be included in a combined grammar wasm"
+                );
+            };
+            let export_name = format!("tree_sitter_{grammar_name}");
+            command.arg(format!("-Wl,--export={export_name}"));
+            export_names.push(export_name);
+            command.args(
+                grammar_sources
+                    .extra_exports
+                    .iter()
+                    .map(|export_name| format!("-Wl,--export={export_name}")),
+            );
+
+            let mut grammar_command = self
+                .grammar_clang_command(options, compile_output.source_date_epoch)
+                .await?;
+            // Clang uses the last `-std` it is given, so this overrides the global standard.
+            if let Some(c_standard) = &grammar_sources.c_standard {
+                grammar_command.arg(format!("-std={c_standard}"));
+            }
+            log::debug!("compiling {grammar_name} parser for the combined grammar wasm");
+            let mut stderr = Vec::new();
+            let object_paths = match self.compile_grammar_objects(
+                &grammar_command,
+                grammar_sources,
+                &object_dir,
+                options.cache_grammar_objects,
+                &mut stderr,
+            )? {
+                Ok(object_paths) => object_paths,
+                Err(clang_output) => {
+                    let clang_stderr = String::from_utf8_lossy(&clang_output.stderr);
+                    return Err(ClangBuildError {
+                        grammar_name: grammar_name.clone(),
+                        diagnostics: parse_clang_diagnostics(&clang_stderr),
+                        stderr: clang_stderr.into_owned(),
+                    }
+                    .into());
+                }
+            };
+            record_grammar_clang_output(
+                grammar_name,
+                &String::from_utf8_lossy(&stderr),
+                options,
+                compile_output,
+            )?;
+            object_grammars.extend(
+                object_paths
+                    .into_iter()
+                    .map(|object_path| (object_path, grammar_name.clone())),
+            );
+        }
+
+        log::debug!(
+            "linking combined grammar wasm for extension {}",
+            extension_dir.display()
+        );
+        let clang_output = self
+            .run_clang(command.args(object_grammars.iter().map(|(object_path, _)| object_path)))
+            .context("failed to run clang")?;
+        if !clang_output.status.success() {
+            let stderr = String::from_utf8_lossy(&clang_output.stderr);
+            let duplicate_symbols = duplicate_grammar_symbols(&stderr, &object_grammars);
+            if !duplicate_symbols.is_empty() {
+                bail!(
+                    "failed to link combined grammar wasm, because grammars define the same \
+                    symbols:\n{}",
+                    duplicate_symbols.join("\n")
+                );
+            }
+            bail!("failed to link combined grammar wasm with clang: {stderr}");
+        }
+
+        let combined_wasm = fs::read(&combined_wasm_path)
+            .with_context(|| format!("failed to read {}", combined_wasm_path.display()))?;
+        let exports = wasm_exports(&combined_wasm)?;
+        for export_name in &export_names {
+            if !exports.contains(export_name) {
+                bail!(
+                    "combined grammar wasm {} does not export `{export_name}`",
+                    combined_wasm_path.display()
+                );
+            }
+        }
+        compile_output.combined_grammar_wasm_size = Some(combined_wasm.len() as u64);
+        compile_output.combined_grammar_exports = export_names;
+
+        Ok(())
+    }
+
+    /// Returns a Clang command with the arguments shared by all grammar builds, downloading the
+    /// `wasi-sdk` if no Clang was configured, or a WASI sysroot for a configured Clang.
+    async fn grammar_clang_command(
+        &self,
+        options: &CompileExtensionOptions,
+        source_date_epoch: Option<u64>,
+    ) -> Result<Command> {
+        if !SUPPORTED_GRAMMAR_CLANG_TARGETS.contains(&self.grammar_clang_target.as_str()) {
+            bail!(
+                "unsupported grammar target `{}`, expected one of: {}",
+                self.grammar_clang_target,
+                SUPPORTED_GRAMMAR_CLANG_TARGETS.join(", ")
+            );
+        }
+
+        let (clang_path, sysroot_dir) = match &self.clang_path {
+            Some(clang_path) => (clang_path.clone(), Some(self.wasi_sysroot_dir().await?)),
+            None => (self.install_wasi_sdk_if_needed().await?, None),
+        };
+
+        let mut command = self.command(&clang_path)?;
+        command
+            // `clang-cl` only accepts MSVC-style arguments unless its driver mode is overridden.
+            .args(is_clang_cl(&clang_path).then_some("--driver-mode=gcc"))
+            .arg(format!("--target={}", self.grammar_clang_target))
+            .args(["-fPIC", "-shared", "-Os"]);
+        if let Some(source_date_epoch) = source_date_epoch {
+            // Clang uses this in place of the current time for `__DATE__` and `__TIME__`.
+            command.env("SOURCE_DATE_EPOCH", source_date_epoch.to_string());
+        }
+        if let Some(sysroot_dir) = sysroot_dir {
+            command.arg("--sysroot").arg(sysroot_dir);
+        }
+        if let Some(grammar_linker) = &self.grammar_linker {
+            self.verify_grammar_linker(grammar_linker)?;
+            let mut ld_path_arg = OsString::from("--ld-path=");
+            ld_path_arg.push(grammar_linker);
+            command.arg(ld_path_arg);
+        }
+        if let Some(c_standard) = &options.grammar_c_standard {
+            command.arg(format!("-std={c_standard}"));
+        }
+        if options.release && options.grammar_lto {
+            command.arg("-flto");
+        }
+        if options.sanitize_grammars {
+            // There is no sanitizer runtime for wasm, so trap on the first violation instead of
+            // reporting it.
+            command.args(["-fsanitize=undefined", "-fsanitize-trap=undefined"]);
+        }
+        Ok(command)
+    }
+
+    /// Checks that the configured grammar linker can be run, so that a missing linker is reported
+    /// as such rather than as a failure to link.
+    fn verify_grammar_linker(&self, grammar_linker: &Path) -> Result<()> {
+        let output = self
+            .command_runner
+            .output(self.command(grammar_linker)?.arg("--version"))
+            .with_context(|| {
+                format!("grammar linker {} was not found", grammar_linker.display())
+            })?;
+        if !output.status.success() {
+            bail!(
+                "grammar linker {} failed to run: {}",
+                grammar_linker.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        log::debug!(
+            "linking grammars with {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+        Ok(())
+    }
+
+    /// Runs a Clang command, retrying it up to [`Self::clang_retries`] times if it fails
+    /// transiently rather than with a compile error.
+    fn run_clang(&self, command: &mut Command) -> io::Result<Output> {
+        let mut retries = 0;
+        loop {
+            let result = self.command_runner.output(command);
+            let is_transient_failure = match &result {
+                // Clang exits with a status code when compilation fails, so only an exit without
+                // one, due to a signal, is transient.
+                Ok(output) => output.status.code().is_none(),
+                // Retrying won't help if Clang isn't installed.
+                Err(error) => error.kind() != io::ErrorKind::NotFound,
+            };
+            if !is_transient_failure || retries >= self.clang_retries {
+                return result;
+            }
+            retries += 1;
+            log::warn!(
+                "clang failed without compiling, retrying ({retries}/{})",
+                self.clang_retries
+            );
+        }
+    }
+
+    /// Compiles each of a grammar's source files to an object, reusing cached objects, and links
+    /// the objects into the grammar's wasm. The output of every Clang invocation is combined,
+    /// as if the grammar had been compiled by a single invocation.
+    fn compile_grammar_with_object_cache(
+        &self,
+        clang_command: &mut Command,
+        grammar_sources: &GrammarSources,
+        grammar_wasm_path: &Path,
+    ) -> Result<Output> {
+        let object_cache_dir = self.cache_dir.join("grammar-objects");
+        fs::create_dir_all(&object_cache_dir).context("failed to create grammar object cache")?;
+
+        let mut stderr = Vec::new();
+        let object_paths = match self.compile_grammar_objects(
+            clang_command,
+            grammar_sources,
+            &object_cache_dir,
+            true,
+            &mut stderr,
+        )? {
+            Ok(object_paths) => object_paths,
+            Err(output) => return Ok(output),
+        };
+
+        let output = self
+            .run_clang(
+                clang_command
+                    .arg("-Qunused-arguments")
+                    .arg("-o")
+                    .arg(grammar_wasm_path)
+                    .args(&object_paths),
+            )
+            .context("failed to run clang")?;
+        stderr.extend(output.stderr);
+        Ok(Output {
+            status: output.status,
+            stdout: output.stdout,
+            stderr,
+        })
+    }
+
+    /// Compiles each of a grammar's source files to an object in `object_dir`, named after a hash
+    /// of its inputs, and returns the objects' paths. When `reuse_objects` is set, objects that
+    /// already exist are used as they are. The stderr of each Clang invocation is appended to
+    /// `stderr`, and if one fails, its output is returned, with `stderr` in place of its own.
+    fn compile_grammar_objects(
+        &self,
+        clang_command: &Command,
+        grammar_sources: &GrammarSources,
+        object_dir: &Path,
+        reuse_objects: bool,
+        stderr: &mut Vec<u8>,
+    ) -> Result<Result<Vec<PathBuf>, Output>> {
+        let mut object_paths = Vec::new();
+        for source_file in &grammar_sources.source_files {
+            let cache_key = grammar_object_cache_key(clang_command, grammar_sources, source_file)?;
+            let object_path = object_dir.join(format!("{cache_key}.o"));
+            if reuse_objects && object_path.is_file() {
+                log::debug!("using cached object for {}", source_file.display());
+            } else {
+                // Compile to a temporary path, so that a failed compilation never leaves a
+                // partial object behind.
+                let partial_object_path = object_path.with_extension("o.partial");
+                let output = self
+                    .run_clang(
+                        self.command(clang_command.get_program())?
+                            .args(clang_command.get_args())
+                            // The linker arguments go unused when only compiling, and the
+                            // compiler arguments when linking.
+                            .args(["-Qunused-arguments", "-c"])
+                            .arg("-o")
+                            .arg(&partial_object_path)
+                            .args(grammar_sources.include_dirs.iter().flat_map(|include_dir| {
+                                [OsStr::new("-I"), include_dir.as_os_str()]
+                            }))
+                            .arg(source_file),
+                    )
+                    .context("failed to run clang")?;
+                stderr.extend(output.stderr);
+                if !output.status.success() {
+                    return Ok(Err(Output {
+                        status: output.status,
+                        stdout: output.stdout,
+                        stderr: mem::take(stderr),
+                    }));
+                }
+                fs::rename(&partial_object_path, &object_path)
+                    .context("failed to move compiled grammar object")?;
+            }
+            object_paths.push(object_path);
+        }
+        Ok(Ok(object_paths))
+    }
+
+    /// Returns the root of the grammar's repository and the directory containing the grammar's
+    /// `src` directory, checking out the repository if it isn't vendored or available locally.
+    ///
+    /// Local repositories are resolved relative to `source_dir`, the extension's original
+    /// directory, which differs from `extension_dir` when building from a copy.
+    pub(super) fn grammar_source_dir(
+        &self,
+        extension_dir: &Path,
+        source_dir: &Path,
+        grammar_name: &str,
+        grammar_metadata: &GrammarManifestEntry,
+    ) -> Result<(PathBuf, PathBuf)> {
+        if let Some(vendored_path) = &grammar_metadata.vendored_path {
+            if !self.allowed_grammar_repositories.is_empty()
+                && (vendored_path.is_absolute()
+                    || vendored_path
+                        .components()
+                        .any(|component| component == Component::ParentDir))
+            {
+                bail!(
+                    "vendored grammar '{grammar_name}' must be within the extension directory \
+                    when grammar repositories are restricted"
+                );
+            }
+            let vendored_dir = extension_dir.join(vendored_path);
+            let grammar_dir = grammar_metadata
+                .path
+                .as_ref()
+                .map(|path| vendored_dir.join(path))
+                .unwrap_or_else(|| vendored_dir.clone());
+            return Ok((vendored_dir, grammar_dir));
+        }
+
+        let repository_dir = if let Some(archive) = &grammar_metadata.archive {
+            let archive_dir = self.grammar_archive_dir(archive);
+            if !archive_dir.is_dir() {
+                bail!("source archive of grammar '{grammar_name}' has not been downloaded");
+            }
+            archive_dir
+        } else if let Some(local_repository_dir) =
+            local_grammar_repository_dir(source_dir, &grammar_metadata.repository)
+        {
+            // Local repositories can't be checked against the allowed URL prefixes, so they
+            // are only allowed when any repository is.
+            if !self.allowed_grammar_repositories.is_empty() {
+                bail!(
+                    "grammar '{grammar_name}' uses the local repository {}, but only repositories \
+                    at allowed URLs may be used",
+                    grammar_metadata.repository
+                );
+            }
+            if !local_repository_dir.is_dir() {
+                bail!(
+                    "grammar repository {} does not exist",
+                    local_repository_dir.display()
+                );
+            }
+            local_repository_dir
+        } else {
+            let checkout_dir_name = if self.namespace_grammar_checkouts {
+                let repository_hash = format!("{:x}", Sha256::digest(&grammar_metadata.repository));
+                format!("{grammar_name}-{}", &repository_hash[..16])
+            } else {
+                grammar_name.to_string()
+            };
+            let mut grammar_repo_dir = extension_dir.to_path_buf();
+            grammar_repo_dir.extend(["grammars", &checkout_dir_name]);
+
+            log::debug!("checking out {grammar_name} parser");
+            self.checkout_repo(
+                &grammar_repo_dir,
+                &grammar_metadata.repository,
+                grammar_rev(grammar_name, grammar_metadata)?,
+            )?;
+            grammar_repo_dir
+        };
+
+        let grammar_dir = grammar_metadata
+            .path
+            .as_ref()
+            .map(|path| repository_dir.join(path))
+            .unwrap_or_else(|| repository_dir.clone());
+        Ok((repository_dir, grammar_dir))
+    }
+
+    /// Regenerates a grammar's `src/parser.c` from its `src/grammar.json` using the Tree-sitter
+    /// CLI, if the parser is missing, or was generated by a previous build from a grammar that has
+    /// since changed.
+    ///
+    /// Modification times aren't compared, as checkouts and archives don't preserve them.
+    async fn generate_grammar_parser_if_needed(
+        &self,
+        grammar_name: &str,
+        grammar_dir: &Path,
+    ) -> Result<()> {
+        let grammar_json_path = grammar_dir.join("src").join("grammar.json");
+        let parser_path = grammar_dir.join("src").join("parser.c");
+        let grammar_hash_path = grammar_dir
+            .join("src")
+            .join(GENERATED_PARSER_GRAMMAR_HASH_FILE);
+        if !grammar_json_path.exists() {
+            return Ok(());
+        }
+
+        let grammar_json = fs::read(&grammar_json_path)
+            .with_context(|| format!("failed to read {}", grammar_json_path.display()))?;
+        let grammar_hash = format!("{:x}", Sha256::digest(&grammar_json));
+        if parser_path.exists() {
+            // Parsers without a recorded hash are committed alongside their grammar, and so are
+            // assumed to be up to date.
+            let is_stale = match fs::read_to_string(&grammar_hash_path) {
+                Ok(generated_from_hash) => generated_from_hash.trim() != grammar_hash,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+                Err(error) => {
+                    return Err(error).with_context(|| {
+                        format!("failed to read {}", grammar_hash_path.display())
+                    });
+                }
+            };
+            if !is_stale {
+                return Ok(());
+            }
+        }
+
+        let tree_sitter_path = if self.download_tree_sitter_cli {
+            self.install_tree_sitter_cli_if_needed().await?
+        } else {
+            PathBuf::from("tree-sitter")
+        };
+
+        log::debug!("generating {grammar_name} parser from grammar.json");
+        let generate_output = match self.command_runner.output(
+            self.command(&tree_sitter_path)?
+                .args(["generate", "src/grammar.json"])
+                .current_dir(grammar_dir),
+        ) {
+            Ok(output) => output,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                bail!(
+                    "grammar '{grammar_name}' must be generated from src/grammar.json, \
+                    but the `tree-sitter` CLI was not found"
+                );
+            }
+            Err(error) => return Err(error).context("failed to run `tree-sitter generate`"),
+        };
+        if !generate_output.status.success() {
+            bail!(
+                "failed to generate {grammar_name} parser: {}",
+                String::from_utf8_lossy(&generate_output.stderr)
+            );
+        }
+        fs::write(&grammar_hash_path, format!("{grammar_hash}\n"))
+            .with_context(|| format!("failed to write {}", grammar_hash_path.display()))?;
+
+        Ok(())
+    }
+
+    /// Copies the source of each of the extension's grammars into `vendor/grammars/<name>`
+    /// and records the vendored location in `extension.toml`, so that subsequent builds
+    /// can compile the grammars without fetching them.
+    ///
+    /// Only the C sources and headers of the directory that the grammar is compiled from are
+    /// vendored, at the same path within the vendored directory as within the repository, so
+    /// that the grammar's `path` still applies. Headers that a scanner includes from elsewhere in
+    /// the repository aren't vendored. `extension.toml` is edited in place, so that its
+    /// formatting and comments are kept.
+    pub async fn vendor_grammars(
+        &self,
+        extension_dir: &Path,
+        extension_manifest: &mut ExtensionManifest,
+    ) -> Result<()> {
+        if extension_manifest.schema_version.is_v0() {
+            bail!("vendoring grammars requires an extension.toml manifest");
+        }
+
+        let manifest_path = extension_dir.join("extension.toml");
+        let mut manifest_content = fs::read_to_string(&manifest_path)
+            .with_context(|| format!("failed to read {}", manifest_path.display()))?;
+
+        for (grammar_name, grammar_metadata) in &mut extension_manifest.grammars {
+            if grammar_metadata.vendored_path.is_some() {
+                continue;
+            }
+            // The grammar's name becomes part of the vendored path, which is removed below.
+            if grammar_name.is_empty()
+                || !grammar_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                bail!("invalid grammar name {grammar_name:?}");
+            }
+
+            if let Some(archive) = &grammar_metadata.archive {
+                self.download_grammar_archive(archive)
+                    .await
+                    .with_context(|| {
+                        format!("failed to download source archive of grammar '{grammar_name}'")
+                    })?;
+            }
+            let (repository_dir, grammar_dir) = self.grammar_source_dir(
+                extension_dir,
+                extension_dir,
+                grammar_name,
+                grammar_metadata,
+            )?;
+
+            let vendored_path = format!("vendor/grammars/{grammar_name}");
+            let vendored_dir = extension_dir.join(&vendored_path);
+            remove_dir_if_exists(&vendored_dir)?;
+            log::info!("vendoring {grammar_name} parser into {vendored_path}");
+            copy_grammar_sources(&repository_dir, &grammar_dir, &vendored_dir)
+                .with_context(|| format!("failed to vendor grammar '{grammar_name}'"))?;
+
+            manifest_content =
+                insert_grammar_vendored_path(&manifest_content, grammar_name, &vendored_path)?;
+            grammar_metadata.vendored_path = Some(PathBuf::from(vendored_path));
+        }
+
+        fs::write(&manifest_path, manifest_content)
+            .with_context(|| format!("failed to write {}", manifest_path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Computes a key identifying the inputs to a grammar's compilation: the Clang program and
+/// arguments, and the contents of the C sources and headers in its include directories.
+fn grammar_artifact_cache_key(
+    clang_command: &Command,
+    grammar_sources: &GrammarSources,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_command(&mut hasher, clang_command);
+    hash_include_dirs(&mut hasher, grammar_sources, &["c", "cc", "h"])?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Computes a key identifying the inputs to the compilation of one of a grammar's source files
+/// to an object: the Clang program and arguments, the source file, and the contents of the
+/// headers in the grammar's include directories.
+fn grammar_object_cache_key(
+    clang_command: &Command,
+    grammar_sources: &GrammarSources,
+    source_file: &Path,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hash_command(&mut hasher, clang_command);
+    let content = fs::read(source_file)
+        .with_context(|| format!("failed to read {}", source_file.display()))?;
+    hasher.update((content.len() as u64).to_le_bytes());
+    hasher.update(content);
+    hash_include_dirs(&mut hasher, grammar_sources, &["h"])?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_command(hasher: &mut Sha256, command: &Command) {
+    hasher.update(command.get_program().as_encoded_bytes());
+    for arg in command.get_args() {
+        hasher.update(arg.as_encoded_bytes());
+        hasher.update([0]);
+    }
+}
+
+/// Hashes the files with the given extensions in a grammar's include directories.
+fn hash_include_dirs(
+    hasher: &mut Sha256,
+    grammar_sources: &GrammarSources,
+    extensions: &[&str],
+) -> Result<()> {
+    for include_dir in &grammar_sources.include_dirs {
+        let mut input_paths = Vec::new();
+        collect_files(include_dir, &mut input_paths)?;
+        input_paths.retain(|path| {
+            path.extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|extension| extensions.contains(&extension))
+        });
+        input_paths.sort();
+
+        for path in input_paths {
+            let content =
+                fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+            // Include each file's path and length, so that moving content between files changes the key.
+            hasher.update(path.strip_prefix(include_dir)?.to_string_lossy().as_bytes());
+            hasher.update((content.len() as u64).to_le_bytes());
+            hasher.update(content);
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the diagnostics and warnings that Clang emitted while successfully compiling a
+/// grammar into `compile_output`, or fails if warnings are denied.
+pub(super) fn record_grammar_clang_output(
+    grammar_name: &str,
+    clang_stderr: &str,
+    options: &CompileExtensionOptions,
+    compile_output: &mut CompileOutput,
+) -> Result<()> {
+    if options.collect_diagnostics {
+        let diagnostics = parse_clang_diagnostics(clang_stderr);
+        if !diagnostics.is_empty() {
+            compile_output
+                .diagnostics
+                .grammars
+                .insert(grammar_name.into(), diagnostics);
+        }
+    }
+
+    let clang_warnings = clang_stderr
+        .lines()
+        .filter(|line| line.contains(": warning: "))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if !clang_warnings.is_empty() {
+        log::info!(
+            "{grammar_name} parser compiled with {} warning(s)",
+            clang_warnings.len()
+        );
+        if options.deny_grammar_warnings {
+            bail!(
+                "{grammar_name} parser compiled with {} warning(s), and warnings are denied:\n{}",
+                clang_warnings.len(),
+                clang_warnings.join("\n")
+            );
+        }
+        compile_output
+            .grammar_warnings
+            .entry(grammar_name.into())
+            .or_default()
+            .extend(clang_warnings);
+    }
+    Ok(())
+}
+
+/// Parses the diagnostics in Clang's output, which are formatted as
+/// `<file>:<line>:<column>: <level>: <message>`, or as `<tool>: <level>: <message>` for those
+/// without a location. Clang has no stable machine-readable diagnostic format, so its default
+/// format is parsed instead.
+pub(super) fn parse_clang_diagnostics(stderr: &str) -> Vec<CompileDiagnostic> {
+    let mut diagnostics = Vec::new();
+    for line in stderr.lines() {
+        let Some((level, location, message)) = ["fatal error", "error", "warning", "note"]
+            .into_iter()
+            .find_map(|level| {
+                let (location, message) = line.split_once(&format!(": {level}: "))?;
+                Some((level, location, message))
+            })
+        else {
+            continue;
+        };
+
+        let mut location_parts = location.rsplitn(3, ':');
+        let (column, line_number, file) = match (
+            location_parts.next().and_then(|column| column.parse().ok()),
+            location_parts.next().and_then(|line| line.parse().ok()),
+            location_parts.next(),
+        ) {
+            (Some(column), Some(line_number), Some(file)) => {
+                (Some(column), Some(line_number), Some(file.to_string()))
+            }
+            _ => (None, None, None),
+        };
+        diagnostics.push(CompileDiagnostic {
+            level: level.to_string(),
+            message: message.to_string(),
+            file,
+            line: line_number,
+            column,
+        });
+    }
+    diagnostics
+}
+
+/// Describes each of the duplicate symbols that `wasm-ld` reported in `stderr`, naming the
+/// grammars whose objects define it. `object_grammars` maps each linked object to its grammar.
+pub(super) fn duplicate_grammar_symbols(
+    stderr: &str,
+    object_grammars: &[(PathBuf, Arc<str>)],
+) -> Vec<String> {
+    let mut duplicates = Vec::new();
+    let mut lines = stderr.lines().peekable();
+    while let Some(line) = lines.next() {
+        let Some((_, symbol)) = line.split_once("duplicate symbol: ") else {
+            continue;
+        };
+        let mut grammar_names = Vec::new();
+        while let Some(object_path) = lines
+            .peek()
+            .and_then(|line| line.trim_start().strip_prefix(">>> defined in "))
+        {
+            let grammar_name = object_grammars
+                .iter()
+                .find(|(path, _)| path.as_os_str() == object_path.trim())
+                .map_or_else(
+                    || object_path.trim().to_string(),
+                    |(_, name)| format!("'{name}'"),
+                );
+            if !grammar_names.contains(&grammar_name) {
+                grammar_names.push(grammar_name);
+            }
+            lines.next();
+        }
+        duplicates.push(format!(
+            "`{}` is defined by {}",
+            symbol.trim(),
+            grammar_names.join(" and ")
+        ));
+    }
+    duplicates
+}
+
+/// Returns whether the Clang at `clang_path` is the MSVC-compatible `clang-cl` front-end.
+pub(super) fn is_clang_cl(clang_path: &Path) -> bool {
+    clang_path
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("clang-cl"))
+}
+
+/// Reads the Tree-sitter ABI version that a generated `parser.c` targets, from its
+/// `LANGUAGE_VERSION` definition.
+fn grammar_abi_version(parser_path: &Path) -> Result<Option<u32>> {
+    let parser_file = fs::File::open(parser_path)
+        .with_context(|| format!("failed to open {}", parser_path.display()))?;
+    for line in io::BufReader::new(parser_file).lines() {
+        if let Some(version) = line?.trim().strip_prefix("#define LANGUAGE_VERSION") {
+            return Ok(version.trim().parse().ok());
+        }
+    }
+    Ok(None)
+}
+
+/// Returns whether the file at `path` is a Git LFS pointer rather than the file's content.
+fn is_lfs_pointer(path: &Path) -> Result<bool> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(error) => {
+            return Err(error).with_context(|| format!("failed to open {}", path.display()));
+        }
+    };
+    let mut header = [0; GIT_LFS_POINTER_HEADER.len()];
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(header == GIT_LFS_POINTER_HEADER),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Checks that a compiled grammar exports its `tree_sitter_<name>` function, since a grammar
+/// without it would only fail once Zed tries to load it.
+pub(super) fn verify_grammar_wasm_exports(
+    grammar_wasm_path: &Path,
+    grammar_name: &str,
+) -> Result<()> {
+    let grammar_wasm = fs::read(grammar_wasm_path)
+        .with_context(|| format!("failed to read {}", grammar_wasm_path.display()))?;
+    let export_name = format!("tree_sitter_{grammar_name}");
+    if !wasm_exports(&grammar_wasm)?.contains(&export_name) {
+        bail!(
+            "compiled grammar {} does not export `{export_name}`",
+            grammar_wasm_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the functions imported by a compiled grammar that aren't in `allowed_imports`.
+pub(super) fn unsupported_grammar_imports(
+    wasm_bytes: &[u8],
+    allowed_imports: &[String],
+) -> Result<Vec<String>> {
+    let mut unsupported_imports = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm_bytes) {
+        if let wasmparser::Payload::ImportSection(reader) = payload? {
+            for import in reader {
+                let import = import?;
+                if matches!(import.ty, wasmparser::TypeRef::Func(_))
+                    && !allowed_imports.iter().any(|allowed| allowed == import.name)
+                {
+                    unsupported_imports.push(import.name.to_string());
+                }
+            }
+        }
+    }
+    Ok(unsupported_imports)
+}
+
+/// Returns the directory that a grammar's parser and scanner are compiled from. Some grammars
+/// have a flat layout, with their parser directly in the grammar directory rather than in `src`,
+/// in which case their headers are also included relative to the grammar directory.
+fn grammar_src_dir(grammar_dir: &Path) -> PathBuf {
+    if !grammar_dir.join("src").join("parser.c").exists() && grammar_dir.join("parser.c").exists() {
+        grammar_dir.to_path_buf()
+    } else {
+        grammar_dir.join("src")
+    }
+}
+
+/// Copies the C sources and headers of the directory that a grammar is compiled from into
+/// `dest_dir`, at the same path relative to it as to `repository_dir`. Generated metadata like
+/// `grammar.json` and `node-types.json` is skipped.
+pub(super) fn copy_grammar_sources(
+    repository_dir: &Path,
+    grammar_dir: &Path,
+    dest_dir: &Path,
+) -> Result<()> {
+    let src_dir = grammar_src_dir(grammar_dir);
+    let relative_src_dir = src_dir.strip_prefix(repository_dir).with_context(|| {
+        format!(
+            "grammar sources {} are outside of the repository {}",
+            src_dir.display(),
+            repository_dir.display()
+        )
+    })?;
+    if !src_dir.is_dir() {
+        bail!("grammar sources {} do not exist", src_dir.display());
+    }
+    copy_grammar_sources_inner(&src_dir, &dest_dir.join(relative_src_dir), dest_dir)
+}
+
+fn copy_grammar_sources_inner(src_dir: &Path, dest_dir: &Path, dest_root: &Path) -> Result<()> {
+    fs::create_dir_all(dest_dir)?;
+    for entry in fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dest_path = dest_dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            // Skip `.git` and other hidden directories, dependencies, and the destination itself,
+            // which is within the repository when the grammar is in the extension's directory.
+            let file_name = entry.file_name();
+            if file_name.to_string_lossy().starts_with('.')
+                || file_name == "node_modules"
+                || path == dest_root
+            {
+                continue;
+            }
+            copy_grammar_sources_inner(&path, &dest_path, dest_root)?;
+        } else if matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("c" | "h")
+        ) {
+            fs::copy(&path, &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds a `vendored_path` key to the grammar's table in the text of an `extension.toml`, which
+/// leaves the rest of the file as it was, unlike re-serializing it. The key is inserted right
+/// after the grammar's `[grammars.<name>]` header, so grammars declared as inline tables aren't
+/// supported.
+pub(super) fn insert_grammar_vendored_path(
+    manifest_content: &str,
+    grammar_name: &str,
+    vendored_path: &str,
+) -> Result<String> {
+    let headers = [
+        format!("[grammars.{grammar_name}]"),
+        format!("[grammars.\"{grammar_name}\"]"),
+        format!("[grammars.'{grammar_name}']"),
+    ];
+    let mut lines = manifest_content.split_inclusive('\n').collect::<Vec<_>>();
+    let header_index = lines
+        .iter()
+        .position(|line| {
+            let line = line.trim();
+            let line = line
+                .split_once('#')
+                .map_or(line, |(line, _)| line.trim_end());
+            headers.iter().any(|header| line == header)
+        })
+        .with_context(|| {
+            format!(
+                "grammar '{grammar_name}' must be declared with a `[grammars.{grammar_name}]` \
+                table in extension.toml to be vendored"
+            )
+        })?;
+
+    let header = lines[header_index];
+    let newline = if header.ends_with("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let separator = if header.ends_with('\n') { "" } else { newline };
+    let indent = &header[..header.len() - header.trim_start().len()];
+    let vendored_path_line = format!(
+        "{separator}{indent}vendored_path = {}{newline}",
+        toml::Value::String(vendored_path.to_string())
+    );
+    lines.insert(header_index + 1, &vendored_path_line);
+    let new_content = lines.concat();
+
+    // Check that the edited manifest is still valid and has the key in the right place.
+    let manifest_toml: toml::Table =
+        toml::from_str(&new_content).context("failed to add `vendored_path` to extension.toml")?;
+    let inserted_path = manifest_toml
+        .get("grammars")
+        .and_then(|grammars| grammars.get(grammar_name))
+        .and_then(|grammar| grammar.get("vendored_path"))
+        .and_then(|vendored_path| vendored_path.as_str());
+    if inserted_path != Some(vendored_path) {
+        bail!("failed to add `vendored_path` to grammar '{grammar_name}' in extension.toml");
+    }
+    Ok(new_content)
+}
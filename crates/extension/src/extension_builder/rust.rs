@@ -0,0 +1,884 @@
+use super::*;
+
+/// Currently, we compile with Rust's `wasm32-wasip2` target, which works with WASI `preview2` and the component model.
+const RUST_TARGET: &str = "wasm32-wasip2";
+
+/// The target used when building extensions for WASI preview 1, whose core modules are then
+/// encoded into components with an adapter.
+const RUST_WASIP1_TARGET: &str = "wasm32-wasip1";
+
+#[derive(Deserialize)]
+struct CargoMetadata {
+    packages: Vec<CargoMetadataPackage>,
+    resolve: CargoMetadataResolve,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataPackage {
+    id: String,
+    name: String,
+    version: String,
+    source: Option<String>,
+    targets: Vec<CargoMetadataTarget>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataTarget {
+    kind: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataResolve {
+    root: Option<String>,
+    nodes: Vec<CargoMetadataNode>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataNode {
+    id: String,
+    deps: Vec<CargoMetadataDep>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataDep {
+    pkg: String,
+    dep_kinds: Vec<CargoMetadataDepKind>,
+}
+
+#[derive(Deserialize)]
+struct CargoMetadataDepKind {
+    kind: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessage {
+    CompilerMessage {
+        message: CargoDiagnostic,
+    },
+    CompilerArtifact {
+        package_id: String,
+        target: CargoArtifactTarget,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct CargoArtifactTarget {
+    name: String,
+}
+
+impl ExtensionBuilder {
+    pub(super) async fn compile_rust_extension(
+        &self,
+        extension_dir: &Path,
+        manifest: &mut ExtensionManifest,
+        options: &CompileExtensionOptions,
+        compile_output: &mut CompileOutput,
+        report_progress: &mut dyn FnMut(BuildPhase, f32),
+    ) -> anyhow::Result<()> {
+        let cargo_toml_path = extension_dir.join("Cargo.toml");
+        if !cargo_toml_path.is_file() {
+            bail!(
+                "manifest declares a Rust library but no Cargo.toml was found at {}",
+                cargo_toml_path.display()
+            );
+        }
+
+        // Check this before building, since a build against an unsupported version would only
+        // produce an extension that Zed can't load.
+        let cargo_toml_content = fs::read_to_string(&cargo_toml_path)
+            .with_context(|| format!("failed to read {}", cargo_toml_path.display()))?;
+        verify_cdylib_crate_type(&cargo_toml_content)?;
+        if let Some(api_version) = extension_api_dependency_version(&cargo_toml_content)? {
+            let supported_api_versions = wasm_api_version_range();
+            if !supported_api_versions.contains(&api_version) {
+                log::warn!(
+                    "extension {} depends on zed_extension_api {api_version}, \
+                    but only versions {} through {} are supported",
+                    manifest.id,
+                    supported_api_versions.start(),
+                    supported_api_versions.end()
+                );
+            }
+        }
+
+        if options.locked {
+            self.prepare_cargo_lock(extension_dir, options)?;
+        }
+
+        let artifact_cache_path = if options.cache_rust_artifacts {
+            let wasip1_adapter_path = self
+                .wasip1_adapter_path
+                .as_deref()
+                .filter(|_| options.wasip1);
+            let cache_key = rust_artifact_cache_key(extension_dir, options, wasip1_adapter_path)?;
+            Some(
+                self.cache_dir
+                    .join("rust-artifacts")
+                    .join(format!("{cache_key}.wasm")),
+            )
+        } else {
+            None
+        };
+
+        let mut component_bytes = match artifact_cache_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+        {
+            Some(component_bytes) => {
+                log::debug!(
+                    "using cached build of Rust crate for extension {}",
+                    extension_dir.display()
+                );
+                component_bytes
+            }
+            None => {
+                let component_bytes = self.build_rust_component(
+                    extension_dir,
+                    options,
+                    compile_output,
+                    report_progress,
+                )?;
+                if let Some(artifact_cache_path) = &artifact_cache_path {
+                    if let Some(parent) = artifact_cache_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::write(artifact_cache_path, &component_bytes)
+                        .context("failed to cache compiled extension")?;
+                }
+                component_bytes
+            }
+        };
+
+        verify_embedded_manifest(&component_bytes, manifest)?;
+
+        if let Err(error) =
+            Validator::new_with_features(HOST_WASM_FEATURES).validate_all(&component_bytes)
+        {
+            if options.deny_unsupported_wasm_features {
+                bail!("extension {} can't be loaded by Zed: {error}", manifest.id);
+            }
+            log::warn!("extension {} may not load in Zed: {error}", manifest.id);
+        }
+
+        if options.stamp_build_info {
+            append_build_info(
+                &mut component_bytes,
+                compile_output.source_state.as_ref(),
+                compile_output.source_date_epoch,
+            )?;
+        }
+
+        let wasm_extension_api_version =
+            parse_wasm_extension_version(&manifest.id, &component_bytes)
+                .context("compiled wasm did not contain a valid zed extension api version")?;
+        if let Some(min_api_version) = options.min_api_version {
+            if wasm_extension_api_version < min_api_version {
+                bail!(
+                    "extension {} targets extension API version {wasm_extension_api_version}, \
+                    but version {min_api_version} or newer is required. \
+                    Update the extension's `zed_extension_api` dependency.",
+                    manifest.id
+                );
+            }
+        }
+        if let Some(pinned_api_version) = manifest.lib.version {
+            if wasm_extension_api_version != pinned_api_version {
+                bail!(
+                    "extension {} pins extension API version {pinned_api_version} in `lib.version`, \
+                    but was built against version {wasm_extension_api_version}. Depend on \
+                    `zed_extension_api` {pinned_api_version}, or update `lib.version` if the \
+                    change of version is intended.",
+                    manifest.id
+                );
+            }
+        }
+        manifest.lib.version = Some(wasm_extension_api_version);
+        compile_output.extension_api_version = Some(wasm_extension_api_version);
+
+        if let Some(max_extension_wasm_size) = self.max_extension_wasm_size {
+            if component_bytes.len() as u64 > max_extension_wasm_size {
+                bail!(
+                    "extension {} compiled to {} bytes, which exceeds the limit of \
+                    {max_extension_wasm_size} bytes",
+                    manifest.id,
+                    component_bytes.len()
+                );
+            }
+        }
+
+        let extension_file = extension_wasm_path(extension_dir, options);
+        match fs::write(&extension_file, &component_bytes) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                bail!(
+                    "permission denied writing {}. Check the permissions of the extension \
+                    directory, or, if it is meant to be read-only, set \
+                    `CompileExtensionOptions::copy_source_to_cache` to build a copy of the \
+                    extension instead.",
+                    extension_file.display()
+                );
+            }
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("failed to write {}", extension_file.display()));
+            }
+        }
+        compile_output.extension_wasm_size = Some(component_bytes.len() as u64);
+
+        let cargo_lock_path = extension_dir.join("Cargo.lock");
+        if cargo_lock_path.exists() {
+            let cargo_lock = fs::read(&cargo_lock_path).context("failed to read Cargo.lock")?;
+            compile_output.cargo_lock_hash = Some(format!("{:x}", Sha256::digest(cargo_lock)));
+        }
+
+        log::debug!(
+            "extension {} written to {}",
+            extension_dir.display(),
+            extension_file.display()
+        );
+
+        Ok(())
+    }
+
+    /// Builds the extension's Rust crate, returning the bytes of the resulting wasm component.
+    ///
+    /// `report_progress` is called with each phase of the build, and how much of the build is
+    /// complete, from `0.0` to `1.0`.
+    fn build_rust_component(
+        &self,
+        extension_dir: &Path,
+        options: &CompileExtensionOptions,
+        compile_output: &mut CompileOutput,
+        report_progress: &mut dyn FnMut(BuildPhase, f32),
+    ) -> Result<Vec<u8>> {
+        let rust_target = rust_target(options);
+        if options.wasip1 && self.wasip1_adapter_path.is_none() {
+            bail!(
+                "building for {RUST_WASIP1_TARGET} requires a WASI preview 1 adapter to encode \
+                the extension as a component. Download `wasi_snapshot_preview1.reactor.wasm` \
+                from a Wasmtime release and set `ExtensionBuilder::wasip1_adapter_path` to it."
+            );
+        }
+
+        let rust_toolchain = options.rust_toolchain.as_deref();
+        if let Some(rust_toolchain) = rust_toolchain {
+            self.ensure_rust_toolchain_installed(rust_toolchain)?;
+        }
+        self.install_rust_wasm_target_if_needed(extension_dir, rust_toolchain, rust_target)?;
+
+        let cargo_toml_content = fs::read_to_string(extension_dir.join("Cargo.toml"))?;
+
+        let total_crates = fs::read_to_string(extension_dir.join("Cargo.lock"))
+            .ok()
+            .and_then(|cargo_lock| cargo_lock_package_count(&cargo_lock));
+
+        log::debug!(
+            "compiling Rust crate for extension {}",
+            extension_dir.display()
+        );
+        // Rustflags from `--config` are added to those from the user's Cargo configuration.
+        let rustflags = rustflags(options)?;
+        let rustflags_config = if rustflags.is_empty() {
+            None
+        } else {
+            // A JSON array of strings is also a valid TOML array.
+            Some(format!(
+                "build.rustflags={}",
+                serde_json::to_string(&rustflags)?
+            ))
+        };
+        let mut errors = Vec::new();
+        let mut warnings = Vec::new();
+        let mut compiled_packages = collections::HashSet::default();
+        let mut dependencies_fetched = false;
+        let mut on_stdout_line = |line: &str| {
+            if !dependencies_fetched {
+                dependencies_fetched = true;
+                report_progress(BuildPhase::RustDependenciesFetched, 0.0);
+            }
+            match serde_json::from_str(line) {
+                Ok(CargoMessage::CompilerMessage { message }) => match message.level.as_str() {
+                    "warning" if !message.is_warning_summary() => {
+                        if options.collect_diagnostics {
+                            compile_output.diagnostics.rust.push((&message).into());
+                        }
+                        warnings.extend(message.rendered);
+                    }
+                    "error" => errors.push(message),
+                    _ => {}
+                },
+                // A package with a build script produces several artifacts, so only count each
+                // package once.
+                Ok(CargoMessage::CompilerArtifact { package_id, target }) => {
+                    if compiled_packages.insert(package_id) {
+                        let compiled_crates = compiled_packages.len();
+                        let fraction_complete = total_crates.map_or(0.0, |total_crates| {
+                            compiled_crates.min(total_crates) as f32 / total_crates as f32
+                        });
+                        report_progress(
+                            BuildPhase::RustCrateCompiled {
+                                crate_name: target.name.into(),
+                                compiled_crates,
+                                total_crates,
+                            },
+                            fraction_complete,
+                        );
+                    }
+                }
+                _ => {}
+            }
+        };
+        let output = self
+            .command_runner
+            .output_with_stdout_lines(
+                self.command("cargo")?
+                    .args(rust_toolchain.map(|toolchain| format!("+{toolchain}")))
+                    .args(["build", "--target", rust_target, "--message-format=json"])
+                    .args(options.release.then_some("--release"))
+                    .args(options.locked.then_some("--locked"))
+                    .args(options.jobs.map(|jobs| format!("--jobs={jobs}")))
+                    .args(
+                        rustflags_config
+                            .iter()
+                            .flat_map(|config| ["--config", config.as_str()]),
+                    )
+                    .arg("--target-dir")
+                    .arg(extension_dir.join("target"))
+                    // WASI builds do not work with sccache and just stuck, so disable it.
+                    .env("RUSTC_WRAPPER", "")
+                    .envs(
+                        compile_output
+                            .source_date_epoch
+                            .map(|timestamp| ("SOURCE_DATE_EPOCH", timestamp.to_string())),
+                    )
+                    .current_dir(extension_dir),
+                &mut on_stdout_line,
+            )
+            .context("failed to run `cargo`")?;
+
+        if !output.status.success() {
+            return Err(CargoBuildError {
+                exit_code: output.status.code(),
+                diagnostics: errors,
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            }
+            .into());
+        }
+
+        if !warnings.is_empty() {
+            log::info!(
+                "extension {} compiled with {} warning(s)",
+                extension_dir.display(),
+                warnings.len()
+            );
+            if options.deny_warnings {
+                bail!(
+                    "extension emitted {} warning(s), and warnings are denied:\n{}",
+                    warnings.len(),
+                    warnings.concat()
+                );
+            }
+        }
+        compile_output.rust_warnings = warnings;
+
+        log::debug!(
+            "compiled Rust crate for extension {}",
+            extension_dir.display()
+        );
+
+        let wasm_path = resolved_wasm_artifact_path(extension_dir, &cargo_toml_content, options)?;
+
+        log::debug!(
+            "encoding wasm component for extension {}",
+            extension_dir.display()
+        );
+
+        let component_bytes = match &self.wasip1_adapter_path {
+            Some(adapter_path) if options.wasip1 => {
+                self.encode_wasip1_component(&wasm_path, adapter_path)?
+            }
+            _ => fs::read(&wasm_path).with_context(|| {
+                format!("failed to read output module `{}`", wasm_path.display())
+            })?,
+        };
+
+        let component_bytes = self
+            .strip_custom_sections(&component_bytes, !options.strip_custom_sections)
+            .context("failed to strip debug sections from wasm component")?;
+
+        if options.strip_custom_sections {
+            wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all())
+                .validate_all(&component_bytes)
+                .context("wasm component is invalid after stripping custom sections")?;
+        }
+
+        Ok(component_bytes)
+    }
+
+    /// Encodes a core module built for WASI preview 1 into a component, using the given adapter
+    /// to implement WASI preview 1 on top of preview 2.
+    fn encode_wasip1_component(&self, module_path: &Path, adapter_path: &Path) -> Result<Vec<u8>> {
+        let component_path = module_path.with_extension("component.wasm");
+        let mut adapt_arg = OsString::from("wasi_snapshot_preview1=");
+        adapt_arg.push(adapter_path);
+        let output = self
+            .command_runner
+            .output(
+                self.command("wasm-tools")?
+                    .args(["component", "new"])
+                    .arg(module_path)
+                    .arg("--adapt")
+                    .arg(adapt_arg)
+                    .arg("-o")
+                    .arg(&component_path),
+            )
+            .context("failed to run `wasm-tools component new`, is `wasm-tools` installed?")?;
+        if !output.status.success() {
+            bail!(
+                "failed to encode {} as a component: {}",
+                module_path.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        fs::read(&component_path)
+            .with_context(|| format!("failed to read component `{}`", component_path.display()))
+    }
+
+    /// Downloads the dependencies of the extension's Rust crate with `cargo fetch`, which also
+    /// updates Cargo's registry index. Running this ahead of [`Self::compile_extension`] keeps
+    /// the index update out of the build itself, so that builds can be timed on their own.
+    pub fn prewarm_cargo_index(
+        &self,
+        extension_dir: &Path,
+        options: &CompileExtensionOptions,
+    ) -> Result<()> {
+        log::info!(
+            "fetching Rust dependencies for extension {}",
+            extension_dir.display()
+        );
+        let output = self
+            .command_runner
+            .output(
+                self.command("cargo")?
+                    .args(
+                        options
+                            .rust_toolchain
+                            .as_ref()
+                            .map(|toolchain| format!("+{toolchain}")),
+                    )
+                    .args(["fetch", "--target", rust_target(options)])
+                    .args(options.locked.then_some("--locked"))
+                    .current_dir(extension_dir),
+            )
+            .context("failed to run `cargo fetch`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to fetch Rust dependencies: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the crates that building the extension's Rust library would compile, sorted by
+    /// name, without building anything.
+    ///
+    /// Cargo's unstable `--build-plan` has been removed, so the plan is derived from the
+    /// dependency graph reported by `cargo metadata` for the wasm target: every package that
+    /// the extension's crate depends on, other than through dev-dependencies.
+    pub fn rust_build_plan(
+        &self,
+        extension_dir: &Path,
+        options: &CompileExtensionOptions,
+    ) -> Result<Vec<PlannedCrate>> {
+        let output = self
+            .command_runner
+            .output(
+                self.command("cargo")?
+                    .args(
+                        options
+                            .rust_toolchain
+                            .as_ref()
+                            .map(|toolchain| format!("+{toolchain}")),
+                    )
+                    .args(["metadata", "--format-version", "1", "--filter-platform"])
+                    .arg(rust_target(options))
+                    .args(options.locked.then_some("--locked"))
+                    .current_dir(extension_dir),
+            )
+            .context("failed to run `cargo metadata`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to read the Rust crate's dependencies: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        build_plan_from_metadata(&output.stdout)
+    }
+
+    /// Ensures that the extension has a `Cargo.lock` to build with `--locked`, by using the
+    /// provided lockfile or generating one if the extension doesn't have one.
+    fn prepare_cargo_lock(
+        &self,
+        extension_dir: &Path,
+        options: &CompileExtensionOptions,
+    ) -> Result<()> {
+        let cargo_lock_path = extension_dir.join("Cargo.lock");
+        if let Some(provided_cargo_lock_path) = &options.cargo_lock_path {
+            fs::copy(provided_cargo_lock_path, &cargo_lock_path).with_context(|| {
+                format!(
+                    "failed to copy {} into extension",
+                    provided_cargo_lock_path.display()
+                )
+            })?;
+        } else if !cargo_lock_path.exists() {
+            log::debug!(
+                "generating Cargo.lock for extension {}",
+                extension_dir.display()
+            );
+            let output = self
+                .command_runner
+                .output(
+                    self.command("cargo")?
+                        .args(
+                            options
+                                .rust_toolchain
+                                .as_ref()
+                                .map(|toolchain| format!("+{toolchain}")),
+                        )
+                        .arg("generate-lockfile")
+                        .current_dir(extension_dir),
+                )
+                .context("failed to run `cargo generate-lockfile`")?;
+            if !output.status.success() {
+                bail!(
+                    "failed to generate Cargo.lock: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn ensure_rust_toolchain_installed(&self, toolchain: &str) -> Result<()> {
+        let output = self
+            .command_runner
+            .output(self.command("rustup")?.args(["toolchain", "list"]))
+            .context("failed to run `rustup toolchain list`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to list installed Rust toolchains: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let toolchain_prefix = format!("{toolchain}-");
+        let is_installed = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .any(|name| name == toolchain || name.starts_with(&toolchain_prefix));
+        if !is_installed {
+            bail!(
+                "Rust toolchain `{toolchain}` is not installed. Install it with `rustup toolchain install {toolchain} --target {RUST_TARGET}`"
+            );
+        }
+
+        Ok(())
+    }
+
+    fn install_rust_wasm_target_if_needed(
+        &self,
+        extension_dir: &Path,
+        rust_toolchain: Option<&str>,
+        rust_target: &str,
+    ) -> Result<()> {
+        // Run from the extension directory, so that we check the toolchain that `rustup` will
+        // use for the build, including one pinned by a `rust-toolchain.toml`.
+        let rustc_output = self
+            .command_runner
+            .output(
+                self.command("rustc")?
+                    .args(rust_toolchain.map(|toolchain| format!("+{toolchain}")))
+                    .arg("--print")
+                    .arg("sysroot")
+                    .current_dir(extension_dir),
+            )
+            .context("failed to run rustc")?;
+        if !rustc_output.status.success() {
+            bail!(
+                "failed to retrieve rust sysroot: {}",
+                String::from_utf8_lossy(&rustc_output.stderr)
+            );
+        }
+
+        let sysroot = PathBuf::from(String::from_utf8(rustc_output.stdout)?.trim());
+        if sysroot.join("lib/rustlib").join(rust_target).exists() {
+            return Ok(());
+        }
+
+        let mut rustup_command = self.command("rustup")?;
+        rustup_command.args(["target", "add", rust_target]);
+        if let Some(rust_toolchain) = rust_toolchain {
+            rustup_command.args(["--toolchain", rust_toolchain]);
+        }
+        let output = self
+            .command_runner
+            .output(
+                rustup_command
+                    .current_dir(extension_dir)
+                    .stderr(Stdio::piped())
+                    .stdout(Stdio::inherit()),
+            )
+            .context("failed to run `rustup target add`")?;
+        if !output.status.success() {
+            bail!(
+                "failed to install the `{rust_target}` target: {}",
+                String::from_utf8_lossy(&rustc_output.stderr)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the target that the extension's Rust crate is built for.
+pub(super) fn rust_target(options: &CompileExtensionOptions) -> &'static str {
+    if options.wasip1 {
+        RUST_WASIP1_TARGET
+    } else {
+        RUST_TARGET
+    }
+}
+
+/// Returns the additional flags to pass to `rustc` when building an extension's Rust library.
+pub(super) fn rustflags(options: &CompileExtensionOptions) -> Result<Vec<String>> {
+    let mut rustflags = Vec::new();
+    if !options.rust_target_features.is_empty() {
+        rustflags.push("-C".to_string());
+        rustflags.push(format!(
+            "target-feature={}",
+            options.rust_target_features.join(",")
+        ));
+    }
+    match &options.pgo {
+        Some(PgoPhase::Instrument { profile_dir }) => {
+            rustflags.push("-C".to_string());
+            rustflags.push(format!("profile-generate={}", profile_dir.display()));
+        }
+        Some(PgoPhase::Optimize { profile_path }) => {
+            if !profile_path.is_file() {
+                bail!(
+                    "profile data {} does not exist. Merge the profiles collected from an \
+                    instrumented build with `llvm-profdata merge` first.",
+                    profile_path.display()
+                );
+            }
+            rustflags.push("-C".to_string());
+            rustflags.push(format!("profile-use={}", profile_path.display()));
+        }
+        None => {}
+    }
+    Ok(rustflags)
+}
+
+/// Computes a key identifying the inputs to a build of the extension's Rust crate, so that
+/// builds of identical sources can reuse a previously built artifact.
+fn rust_artifact_cache_key(
+    extension_dir: &Path,
+    options: &CompileExtensionOptions,
+    wasip1_adapter_path: Option<&Path>,
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(rust_target(options));
+    // Builds with warnings denied only cache artifacts that built without warnings, which
+    // builds with warnings allowed could otherwise have cached.
+    hasher.update([
+        u8::from(options.release),
+        u8::from(options.strip_custom_sections),
+        u8::from(options.deny_warnings),
+    ]);
+    hasher.update(options.rust_toolchain.as_deref().unwrap_or_default());
+    hasher.update(options.rust_target_features.join(","));
+    match &options.pgo {
+        Some(PgoPhase::Instrument { profile_dir }) => {
+            hasher.update(b"profile-generate");
+            hasher.update(profile_dir.as_os_str().as_encoded_bytes());
+        }
+        Some(PgoPhase::Optimize { profile_path }) => {
+            hasher.update(b"profile-use");
+            hasher.update(fs::read(profile_path).with_context(|| {
+                format!("failed to read profile data {}", profile_path.display())
+            })?);
+        }
+        None => {}
+    }
+
+    if let Some(wasip1_adapter_path) = wasip1_adapter_path {
+        hasher.update(fs::read(wasip1_adapter_path).with_context(|| {
+            format!(
+                "failed to read WASI preview 1 adapter {}",
+                wasip1_adapter_path.display()
+            )
+        })?);
+    }
+
+    let mut input_paths = [
+        "Cargo.toml",
+        "Cargo.lock",
+        "build.rs",
+        "rust-toolchain",
+        "rust-toolchain.toml",
+        ".cargo/config",
+        ".cargo/config.toml",
+    ]
+    .into_iter()
+    .map(|file_name| extension_dir.join(file_name))
+    .filter(|path| path.is_file())
+    .collect::<Vec<_>>();
+    collect_files(&extension_dir.join("src"), &mut input_paths)?;
+    input_paths.sort();
+
+    for path in input_paths {
+        let content =
+            fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        // Include each file's path and length, so that moving content between files changes the key.
+        hasher.update(
+            path.strip_prefix(extension_dir)?
+                .to_string_lossy()
+                .as_bytes(),
+        );
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the packages that the root package of `cargo metadata` output depends on, directly or
+/// transitively, other than through dev-dependencies, including the root package itself.
+pub(super) fn build_plan_from_metadata(metadata: &[u8]) -> Result<Vec<PlannedCrate>> {
+    let metadata: CargoMetadata =
+        serde_json::from_slice(metadata).context("failed to parse `cargo metadata` output")?;
+    let root = metadata
+        .resolve
+        .root
+        .context("the extension's Cargo.toml has no package")?;
+    let nodes = metadata
+        .resolve
+        .nodes
+        .iter()
+        .map(|node| (node.id.as_str(), node))
+        .collect::<HashMap<_, _>>();
+
+    let mut planned_ids = collections::HashSet::default();
+    let mut pending_ids = vec![root.as_str()];
+    while let Some(id) = pending_ids.pop() {
+        if !planned_ids.insert(id) {
+            continue;
+        }
+        let Some(node) = nodes.get(id) else {
+            continue;
+        };
+        pending_ids.extend(
+            node.deps
+                .iter()
+                .filter(|dep| {
+                    dep.dep_kinds
+                        .iter()
+                        .any(|dep_kind| dep_kind.kind.as_deref() != Some("dev"))
+                })
+                .map(|dep| dep.pkg.as_str()),
+        );
+    }
+
+    let mut planned_crates = metadata
+        .packages
+        .iter()
+        .filter(|package| planned_ids.contains(package.id.as_str()))
+        .map(|package| PlannedCrate {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source.clone(),
+            has_build_script: package
+                .targets
+                .iter()
+                .any(|target| target.kind.iter().any(|kind| kind == "custom-build")),
+        })
+        .collect::<Vec<_>>();
+    planned_crates.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.version.cmp(&b.version)));
+    Ok(planned_crates)
+}
+
+/// Returns the number of packages in a `Cargo.lock`.
+pub(super) fn cargo_lock_package_count(cargo_lock: &str) -> Option<usize> {
+    let cargo_lock: toml::Table = toml::from_str(cargo_lock).ok()?;
+    Some(cargo_lock.get("package")?.as_array()?.len())
+}
+
+/// Checks that an extension's `Cargo.toml` builds its library as a `cdylib`, which is what
+/// produces the wasm that Zed loads. Without it, Cargo only builds an `.rlib`.
+pub(super) fn verify_cdylib_crate_type(cargo_toml_content: &str) -> Result<()> {
+    let cargo_toml: toml::Table =
+        toml::from_str(cargo_toml_content).context("failed to parse Cargo.toml")?;
+    let crate_types = cargo_toml
+        .get("lib")
+        .and_then(|lib| lib.get("crate-type"))
+        .and_then(|crate_types| crate_types.as_array());
+    let is_cdylib = crate_types.is_some_and(|crate_types| {
+        crate_types
+            .iter()
+            .any(|crate_type| crate_type.as_str() == Some("cdylib"))
+    });
+    if !is_cdylib {
+        bail!(
+            "the extension's Cargo.toml must build a `cdylib` library for Zed to load. \
+            Add the following to Cargo.toml:\n\n[lib]\ncrate-type = [\"cdylib\"]"
+        );
+    }
+    Ok(())
+}
+
+/// Returns the version of `zed_extension_api` that an extension's `Cargo.toml` depends on, or
+/// `None` if it doesn't depend on it or its version requirement isn't a plain version such as
+/// `0.5` or `=0.5.0`.
+pub(super) fn extension_api_dependency_version(
+    cargo_toml_content: &str,
+) -> Result<Option<SemanticVersion>> {
+    let cargo_toml: toml::Table =
+        toml::from_str(cargo_toml_content).context("failed to parse Cargo.toml")?;
+    let Some(dependency) = cargo_toml
+        .get("dependencies")
+        .and_then(|dependencies| dependencies.get("zed_extension_api"))
+    else {
+        return Ok(None);
+    };
+    let requirement = match dependency {
+        toml::Value::String(version) => version.as_str(),
+        toml::Value::Table(dependency) => {
+            match dependency
+                .get("version")
+                .and_then(|version| version.as_str())
+            {
+                Some(version) => version,
+                None => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    let components = requirement
+        .trim()
+        .trim_start_matches(['^', '=', '~'])
+        .split('.')
+        .map(|component| component.parse::<usize>().ok())
+        .collect::<Option<Vec<_>>>();
+    Ok(match components.as_deref() {
+        Some([major]) => Some(SemanticVersion::new(*major, 0, 0)),
+        Some([major, minor]) => Some(SemanticVersion::new(*major, *minor, 0)),
+        Some([major, minor, patch]) => Some(SemanticVersion::new(*major, *minor, *patch)),
+        _ => None,
+    })
+}
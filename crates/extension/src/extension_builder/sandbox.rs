@@ -0,0 +1,202 @@
+use super::*;
+
+/// The environment variables that commands inherit from the current process when
+/// [`ExtensionBuilder::sandbox_builds`] is set. These are only those needed to locate the
+/// toolchains and to make network requests.
+pub(super) const SANDBOX_ENV_VARS: &[&str] = &[
+    "PATH",
+    "HOME",
+    "CARGO_HOME",
+    "RUSTUP_HOME",
+    "RUSTUP_TOOLCHAIN",
+    "SSL_CERT_FILE",
+    "SYSTEMROOT",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+];
+
+/// The directory that an extension is built in when [`ExtensionBuilder::sandbox_builds`] is set.
+/// The copy of the extension's source within it is removed once a build succeeds, or failing
+/// that, when this is dropped, which can only log an error removing it.
+pub(super) struct Sandbox {
+    pub(super) dir: PathBuf,
+}
+
+impl Sandbox {
+    pub(super) fn source_dir(&self) -> PathBuf {
+        self.dir.join("source")
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let source_dir = self.source_dir();
+        if let Err(error) = fs::remove_dir_all(&source_dir) {
+            if error.kind() != io::ErrorKind::NotFound {
+                log::warn!("failed to remove sandbox {}: {error}", source_dir.display());
+            }
+        }
+    }
+}
+
+/// Removes `dir` and everything in it, if it exists.
+pub(super) fn remove_dir_if_exists(dir: &Path) -> Result<()> {
+    match fs::remove_dir_all(dir) {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error).with_context(|| format!("failed to remove {}", dir.display())),
+    }
+}
+
+/// Copies an extension's source into `build_dir`, replacing anything from an earlier copy but
+/// keeping its `target` directory, so that builds of the copy remain incremental.
+///
+/// Symlinks are copied as the files or directories they point to, which must be within
+/// `source_dir`, so that a build of the copy can't reach files outside of the extension.
+pub(super) fn copy_extension_source(source_dir: &Path, build_dir: &Path) -> Result<()> {
+    if build_dir.exists() {
+        for entry in fs::read_dir(build_dir)? {
+            let entry = entry?;
+            if entry.file_name() == "target" {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        }
+    }
+
+    let source_dir = source_dir
+        .canonicalize()
+        .with_context(|| format!("failed to canonicalize {}", source_dir.display()))?;
+    copy_extension_source_dir(
+        &source_dir,
+        &source_dir,
+        build_dir,
+        &mut vec![source_dir.clone()],
+    )
+}
+
+/// Copies the directory `dir`, within the extension source at `source_dir`, to `dest_dir`.
+/// `visiting` holds the canonical paths of the directories being copied, to detect symlink
+/// cycles.
+fn copy_extension_source_dir(
+    source_dir: &Path,
+    dir: &Path,
+    dest_dir: &Path,
+    visiting: &mut Vec<PathBuf>,
+) -> Result<()> {
+    fs::create_dir_all(dest_dir)
+        .with_context(|| format!("failed to create {}", dest_dir.display()))?;
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let entry = entry?;
+        if dir == source_dir && entry.file_name() == "target" {
+            continue;
+        }
+        let dest_path = dest_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        let (path, is_dir) = if file_type.is_symlink() {
+            let link_path = entry.path();
+            let target_path = link_path
+                .canonicalize()
+                .with_context(|| format!("failed to resolve symlink {}", link_path.display()))?;
+            if !target_path.starts_with(source_dir) {
+                bail!(
+                    "symlink {} points outside of the extension directory",
+                    link_path.display()
+                );
+            }
+            let is_dir = target_path.is_dir();
+            (target_path, is_dir)
+        } else {
+            (entry.path(), file_type.is_dir())
+        };
+
+        if is_dir {
+            if visiting.contains(&path) {
+                bail!(
+                    "symlink {} creates a cycle",
+                    dir.join(entry.file_name()).display()
+                );
+            }
+            visiting.push(path.clone());
+            copy_extension_source_dir(source_dir, &path, &dest_path, visiting)?;
+            visiting.pop();
+        } else {
+            fs::copy(&path, &dest_path)
+                .with_context(|| format!("failed to copy {}", path.display()))?;
+            // The build writes to some of the copied files, such as `Cargo.lock`, so copies of
+            // read-only files are made writable while keeping their other permissions.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+                let mut permissions = fs::metadata(&dest_path)?.permissions();
+                if permissions.mode() & 0o200 == 0 {
+                    permissions.set_mode(permissions.mode() | 0o200);
+                    fs::set_permissions(&dest_path, permissions)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies the artifacts of an extension built in a sandbox into `artifacts_dir`: its
+/// `extension.wasm` and grammar wasms, including the combined grammar wasm. Anything else that
+/// the build wrote is left behind.
+pub(super) fn collect_sandbox_artifacts(
+    extension_dir: &Path,
+    artifacts_dir: &Path,
+    manifest: &ExtensionManifest,
+    options: &CompileExtensionOptions,
+) -> Result<()> {
+    let mut artifact_paths = vec![extension_wasm_path(extension_dir, options)];
+    artifact_paths.extend(
+        manifest
+            .grammars
+            .keys()
+            .map(|grammar_name| grammar_wasm_path(extension_dir, grammar_name, options)),
+    );
+    if options.combine_grammars {
+        artifact_paths.push(combined_grammar_wasm_path(extension_dir, options));
+    }
+
+    let canonical_extension_dir = extension_dir
+        .canonicalize()
+        .with_context(|| format!("failed to canonicalize {}", extension_dir.display()))?;
+    for artifact_path in artifact_paths {
+        // The build could replace an artifact, or a directory containing it, with a symlink to a
+        // file outside of the sandbox, so artifacts are only collected from within it.
+        match fs::symlink_metadata(&artifact_path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                bail!("artifact {} is a symlink", artifact_path.display())
+            }
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => continue,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("failed to read {}", artifact_path.display()));
+            }
+        }
+        let canonical_artifact_path = artifact_path
+            .canonicalize()
+            .with_context(|| format!("failed to canonicalize {}", artifact_path.display()))?;
+        if !canonical_artifact_path.starts_with(&canonical_extension_dir) {
+            bail!(
+                "artifact {} is outside of the sandbox",
+                artifact_path.display()
+            );
+        }
+        let dest_path = artifacts_dir.join(artifact_path.strip_prefix(extension_dir)?);
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(&artifact_path, &dest_path)
+            .with_context(|| format!("failed to copy {}", artifact_path.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,498 @@
+use super::*;
+
+/// The only string-valued properties of a theme's style that aren't colors.
+const THEME_STYLE_NON_COLOR_KEYS: &[&str] = &["background.appearance", "font_style"];
+
+impl ExtensionBuilder {
+    /// Validates every extension under `root_dir`, such as all of those in a monorepo, without
+    /// building them. Each directory with an `extension.toml` or `extension.json` is an
+    /// extension; directories within an extension aren't searched for further extensions.
+    ///
+    /// Each extension's manifest is loaded and filled in as it would be for a build, then its
+    /// themes, icon themes, snippets, languages and grammar repositories are checked. Every
+    /// problem is reported, rather than only the first.
+    pub fn validate_extensions(&self, root_dir: &Path) -> Result<ValidationReport> {
+        let mut extension_dirs = Vec::new();
+        find_extension_dirs(root_dir, &mut extension_dirs)
+            .with_context(|| format!("failed to search {} for extensions", root_dir.display()))?;
+
+        let mut report = ValidationReport::default();
+        for extension_dir in extension_dirs {
+            let errors = self.validate_extension(&extension_dir);
+            let relative_extension_dir = extension_dir.strip_prefix(root_dir)?.to_path_buf();
+            if !errors.is_empty() {
+                report.errors.insert(relative_extension_dir.clone(), errors);
+            }
+            report.extension_dirs.push(relative_extension_dir);
+        }
+        Ok(report)
+    }
+
+    /// Returns the problems found in the extension in `extension_dir`.
+    fn validate_extension(&self, extension_dir: &Path) -> Vec<String> {
+        let mut manifest = match read_extension_manifest(extension_dir) {
+            Ok(manifest) => manifest,
+            Err(error) => return vec![format!("{error:#}")],
+        };
+        if let Err(error) = populate_defaults(&mut manifest, extension_dir) {
+            return vec![format!("{error:#}")];
+        }
+
+        let mut errors = Vec::new();
+        if manifest.grammars.len() > self.max_grammars {
+            errors.push(format!(
+                "declares {} grammars, which exceeds the limit of {}",
+                manifest.grammars.len(),
+                self.max_grammars
+            ));
+        }
+        for (grammar_name, grammar_metadata) in &manifest.grammars {
+            let urls = [
+                Some(&grammar_metadata.repository),
+                grammar_metadata.prebuilt_url.as_ref(),
+                grammar_metadata
+                    .archive
+                    .as_ref()
+                    .map(|archive| &archive.url),
+            ];
+            for url in urls.into_iter().flatten() {
+                if let Err(error) = self.check_grammar_repository_allowed(url) {
+                    errors.push(format!("grammar '{grammar_name}': {error:#}"));
+                }
+            }
+        }
+        for result in [
+            validate_themes(extension_dir, &manifest),
+            validate_icon_themes(extension_dir, &manifest),
+            validate_snippets(extension_dir, &manifest),
+            validate_languages(extension_dir, &manifest),
+        ] {
+            if let Err(error) = result {
+                errors.push(format!("{error:#}"));
+            }
+        }
+        errors
+    }
+}
+
+/// Checks that a prebuilt extension's wasm can be loaded by Zed and belongs to the extension
+/// described by `manifest`.
+pub(super) fn validate_prebuilt_extension(
+    wasm_bytes: &[u8],
+    manifest: &ExtensionManifest,
+) -> Result<()> {
+    Validator::new_with_features(HOST_WASM_FEATURES)
+        .validate_all(wasm_bytes)
+        .context("invalid wasm")?;
+    let api_version = parse_wasm_extension_version(&manifest.id, wasm_bytes)?;
+    if let Some(manifest_api_version) = manifest.lib.version {
+        if api_version != manifest_api_version {
+            bail!(
+                "wasm targets extension API version {api_version}, \
+                but the manifest declares version {manifest_api_version}"
+            );
+        }
+    }
+    verify_embedded_manifest(wasm_bytes, manifest)
+}
+
+/// Checks that the grammar referenced by each of the extension's languages was compiled, so that
+/// languages don't silently fail to load because their grammar is missing.
+pub(super) fn verify_language_grammars(
+    extension_dir: &Path,
+    manifest: &ExtensionManifest,
+    output: &CompileOutput,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for relative_language_dir in &manifest.languages {
+        let config_path = extension_dir
+            .join(relative_language_dir)
+            .join("config.toml");
+        let config_content = fs::read_to_string(&config_path)
+            .with_context(|| format!("failed to read {}", config_path.display()))?;
+        let config: toml::Table = toml::from_str(&config_content)
+            .with_context(|| format!("invalid {}", config_path.display()))?;
+        let Some(grammar_name) = config.get("grammar").and_then(|grammar| grammar.as_str()) else {
+            continue;
+        };
+        if !output.grammar_wasm_sizes.contains_key(grammar_name) {
+            errors.push(format!(
+                "{}: grammar '{grammar_name}' was not built",
+                relative_language_dir.display()
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "languages reference missing grammars:\n{}",
+            errors.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Validates each of the extension's themes against the theme schema, so that invalid themes are
+/// reported at build time rather than silently failing to load.
+pub(super) fn validate_themes(extension_dir: &Path, manifest: &ExtensionManifest) -> Result<()> {
+    let mut errors = Vec::new();
+    for relative_theme_path in &manifest.themes {
+        let theme_path = extension_dir.join(relative_theme_path);
+        let theme_content = fs::read_to_string(&theme_path)
+            .with_context(|| format!("failed to read theme {}", theme_path.display()))?;
+        let theme_family: serde_json::Value = match serde_json_lenient::from_str(&theme_content) {
+            Ok(theme_family) => theme_family,
+            Err(error) => {
+                errors.push(format!("{}: {error}", relative_theme_path.display()));
+                continue;
+            }
+        };
+        let missing_fields = missing_theme_fields(&theme_family);
+        if !missing_fields.is_empty() {
+            for field in missing_fields {
+                errors.push(format!(
+                    "{}: missing required field `{field}`",
+                    relative_theme_path.display()
+                ));
+            }
+            continue;
+        }
+        let theme_family: ThemeFamilyContent = match serde_json::from_value(theme_family) {
+            Ok(theme_family) => theme_family,
+            Err(error) => {
+                errors.push(format!("{}: {error}", relative_theme_path.display()));
+                continue;
+            }
+        };
+
+        for theme in &theme_family.themes {
+            let mut invalid_colors = Vec::new();
+            collect_invalid_theme_colors(
+                &serde_json::to_value(&theme.style)?,
+                "style".to_string(),
+                &mut invalid_colors,
+            );
+            for (field, color) in invalid_colors {
+                errors.push(format!(
+                    "{}: theme \"{}\": invalid color {color:?} for `{field}`",
+                    relative_theme_path.display(),
+                    theme.name
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("invalid themes:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Returns the path of each required field that is missing from a theme family, or that has the
+/// wrong JSON type, such as `author` or `themes[1].appearance`.
+pub(super) fn missing_theme_fields(theme_family: &serde_json::Value) -> Vec<String> {
+    let mut missing_fields = Vec::new();
+    for field in ["name", "author"] {
+        if !theme_family
+            .get(field)
+            .is_some_and(|value| value.is_string())
+        {
+            missing_fields.push(field.to_string());
+        }
+    }
+    let Some(themes) = theme_family
+        .get("themes")
+        .and_then(|themes| themes.as_array())
+    else {
+        missing_fields.push("themes".to_string());
+        return missing_fields;
+    };
+    for (ix, theme) in themes.iter().enumerate() {
+        for field in ["name", "appearance"] {
+            if !theme.get(field).is_some_and(|value| value.is_string()) {
+                missing_fields.push(format!("themes[{ix}].{field}"));
+            }
+        }
+        if !theme.get("style").is_some_and(|style| style.is_object()) {
+            missing_fields.push(format!("themes[{ix}].style"));
+        }
+    }
+    missing_fields
+}
+
+/// Writes each of the extension's themes into `output_dir`, at the same path relative to it as
+/// within the extension directory, with its colors normalized by [`normalize_theme_colors`] and
+/// its JSON reformatted. Anything from an earlier build in `output_dir` is removed first.
+pub(super) fn write_normalized_themes(
+    extension_dir: &Path,
+    manifest: &ExtensionManifest,
+    output_dir: &Path,
+) -> Result<()> {
+    if output_dir.exists() {
+        fs::remove_dir_all(output_dir)
+            .with_context(|| format!("failed to remove {}", output_dir.display()))?;
+    }
+    for relative_theme_path in &manifest.themes {
+        if relative_theme_path.is_absolute()
+            || relative_theme_path
+                .components()
+                .any(|component| component == Component::ParentDir)
+        {
+            bail!(
+                "theme path {} must be within the extension directory",
+                relative_theme_path.display()
+            );
+        }
+        let theme_path = extension_dir.join(relative_theme_path);
+        let theme_content = fs::read_to_string(&theme_path)
+            .with_context(|| format!("failed to read theme {}", theme_path.display()))?;
+        // The theme is rewritten from its raw JSON, so that fields that Zed doesn't know about
+        // yet are kept.
+        let mut theme_family: serde_json::Value = serde_json_lenient::from_str(&theme_content)
+            .with_context(|| format!("invalid theme {}", theme_path.display()))?;
+        if let Some(themes) = theme_family
+            .get_mut("themes")
+            .and_then(|themes| themes.as_array_mut())
+        {
+            for theme in themes {
+                if let Some(style) = theme.get_mut("style") {
+                    normalize_theme_colors(style);
+                }
+            }
+        }
+        let mut normalized_content = serde_json::to_string_pretty(&theme_family)?;
+        normalized_content.push('\n');
+
+        let normalized_theme_path = output_dir.join(relative_theme_path);
+        if let Some(parent) = normalized_theme_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        fs::write(&normalized_theme_path, normalized_content).with_context(|| {
+            format!("failed to write theme {}", normalized_theme_path.display())
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks that every icon referenced by each of the extension's icon themes is a file in the
+/// extension's `icons` directory, which is the only directory of icons that gets packaged.
+pub(super) fn validate_icon_themes(
+    extension_dir: &Path,
+    manifest: &ExtensionManifest,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for relative_icon_theme_path in &manifest.icon_themes {
+        let icon_theme_path = extension_dir.join(relative_icon_theme_path);
+        let icon_theme_content = fs::read_to_string(&icon_theme_path)
+            .with_context(|| format!("failed to read icon theme {}", icon_theme_path.display()))?;
+        let icon_theme_family: IconThemeFamilyContent =
+            match serde_json_lenient::from_str(&icon_theme_content) {
+                Ok(icon_theme_family) => icon_theme_family,
+                Err(error) => {
+                    errors.push(format!("{}: {error}", relative_icon_theme_path.display()));
+                    continue;
+                }
+            };
+
+        for icon_theme in &icon_theme_family.themes {
+            for icon_path in icon_theme_icon_paths(icon_theme) {
+                let problem = if !Path::new(icon_path).starts_with("icons") {
+                    "is not in the `icons` directory"
+                } else if !extension_dir.join(icon_path).is_file() {
+                    "does not exist"
+                } else {
+                    continue;
+                };
+                errors.push(format!(
+                    "{}: icon theme \"{}\": icon {icon_path:?} {problem}",
+                    relative_icon_theme_path.display(),
+                    icon_theme.name
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("invalid icon themes:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Returns the distinct paths of the icons that an icon theme references, relative to the
+/// extension directory, in sorted order.
+pub(super) fn icon_theme_icon_paths(icon_theme: &IconThemeContent) -> Vec<&str> {
+    let mut icon_paths = [
+        &icon_theme.directory_icons.collapsed,
+        &icon_theme.directory_icons.expanded,
+        &icon_theme.chevron_icons.collapsed,
+        &icon_theme.chevron_icons.expanded,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(icon_theme.file_icons.values().map(|icon| &icon.path))
+    .map(|icon_path| -> &str { icon_path.as_ref() })
+    .collect::<Vec<_>>();
+    icon_paths.sort_unstable();
+    icon_paths.dedup();
+    icon_paths
+}
+
+/// Validates the `config.toml` of each of the extension's languages, so that invalid configs are
+/// reported at build time rather than failing to load.
+pub(super) fn validate_languages(extension_dir: &Path, manifest: &ExtensionManifest) -> Result<()> {
+    let mut errors = Vec::new();
+    for relative_language_dir in &manifest.languages {
+        let relative_config_path = relative_language_dir.join("config.toml");
+        let config_path = extension_dir.join(&relative_config_path);
+        let config_content = match fs::read_to_string(&config_path) {
+            Ok(config_content) => config_content,
+            Err(error) => {
+                errors.push(format!("{}: {error}", relative_config_path.display()));
+                continue;
+            }
+        };
+        let config: LanguageConfig = match toml::from_str(&config_content) {
+            Ok(config) => config,
+            Err(error) => {
+                errors.push(format!("{}: {error}", relative_config_path.display()));
+                continue;
+            }
+        };
+        if config.matcher.path_suffixes.is_empty() && config.matcher.first_line_pattern.is_none() {
+            errors.push(format!(
+                "{}: language \"{}\" has neither `path_suffixes` nor `first_line_pattern`, \
+                so no files will use it",
+                relative_config_path.display(),
+                config.name
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!("invalid language configs:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Validates the extension's snippets file, so that a malformed file is reported at build time
+/// rather than its snippets silently failing to load.
+pub(super) fn validate_snippets(extension_dir: &Path, manifest: &ExtensionManifest) -> Result<()> {
+    let Some(relative_snippets_path) = &manifest.snippets else {
+        return Ok(());
+    };
+    let snippets_path = extension_dir.join(relative_snippets_path);
+    let snippets_content = fs::read_to_string(&snippets_path)
+        .with_context(|| format!("failed to read snippets {}", snippets_path.display()))?;
+    let snippets: serde_json::Value = serde_json_lenient::from_str(&snippets_content)
+        .with_context(|| format!("invalid snippets {}", snippets_path.display()))?;
+    let snippets = snippets.as_object().with_context(|| {
+        format!(
+            "invalid snippets {}: expected an object",
+            snippets_path.display()
+        )
+    })?;
+
+    let is_text = |value: &serde_json::Value| match value {
+        serde_json::Value::String(_) => true,
+        serde_json::Value::Array(lines) => lines.iter().all(serde_json::Value::is_string),
+        _ => false,
+    };
+    let mut errors = Vec::new();
+    for (snippet_name, snippet) in snippets {
+        let Some(snippet) = snippet.as_object() else {
+            errors.push(format!("snippet \"{snippet_name}\": expected an object"));
+            continue;
+        };
+        match snippet.get("body") {
+            Some(body) if is_text(body) => {}
+            Some(_) => errors.push(format!(
+                "snippet \"{snippet_name}\": `body` must be a string or a list of strings"
+            )),
+            None => errors.push(format!("snippet \"{snippet_name}\": missing `body`")),
+        }
+        for field in ["prefix", "description"] {
+            if snippet
+                .get(field)
+                .is_some_and(|value| !value.is_null() && !is_text(value))
+            {
+                errors.push(format!(
+                    "snippet \"{snippet_name}\": `{field}` must be a string or a list of strings"
+                ));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "invalid snippets {}:\n{}",
+            snippets_path.display(),
+            errors.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Rewrites every color in a theme's style as `#rrggbbaa`, whichever of the supported formats it
+/// was written in. Strings that aren't valid colors are left as they are.
+pub(super) fn normalize_theme_colors(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(color) => {
+            if let Ok(rgba) = gpui::Rgba::try_from(color.as_str()) {
+                let byte = |component: f32| (component * 255.0).round() as u8;
+                *color = format!(
+                    "#{:02x}{:02x}{:02x}{:02x}",
+                    byte(rgba.r),
+                    byte(rgba.g),
+                    byte(rgba.b),
+                    byte(rgba.a)
+                );
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                normalize_theme_colors(item);
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                if !THEME_STYLE_NON_COLOR_KEYS.contains(&key.as_str()) {
+                    normalize_theme_colors(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn collect_invalid_theme_colors(
+    value: &serde_json::Value,
+    field: String,
+    invalid_colors: &mut Vec<(String, String)>,
+) {
+    match value {
+        serde_json::Value::String(color) => {
+            if gpui::Rgba::try_from(color.as_str()).is_err() {
+                invalid_colors.push((field, color.clone()));
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (ix, item) in items.iter().enumerate() {
+                collect_invalid_theme_colors(item, format!("{field}[{ix}]"), invalid_colors);
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                if THEME_STYLE_NON_COLOR_KEYS.contains(&key.as_str()) {
+                    continue;
+                }
+                collect_invalid_theme_colors(value, format!("{field}.{key}"), invalid_colors);
+            }
+        }
+        _ => {}
+    }
+}
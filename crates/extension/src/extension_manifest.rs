@@ -91,6 +91,12 @@ pub struct ExtensionManifest {
     pub debug_adapters: BTreeMap<Arc<str>, DebugAdapterManifestEntry>,
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub debug_locators: BTreeMap<Arc<str>, DebugLocatorManifestEntry>,
+    /// The oldest version of Zed that the extension works with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_zed_version: Option<SemanticVersion>,
+    /// A command to run in the extension directory once the extension has been compiled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_build: Option<PostBuildManifestEntry>,
 }
 
 impl ExtensionManifest {
@@ -149,6 +155,9 @@ pub enum ExtensionCapability {
 #[derive(Clone, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct LibManifestEntry {
     pub kind: Option<ExtensionLibraryKind>,
+    /// The extension API version that the library targets. When declared in the manifest, the
+    /// build fails unless the compiled library targets exactly this version. Otherwise, it is
+    /// filled in from the compiled library.
     pub version: Option<SemanticVersion>,
 }
 
@@ -159,11 +168,42 @@ pub enum ExtensionLibraryKind {
 
 #[derive(Clone, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct GrammarManifestEntry {
+    /// The URL of the grammar's git repository, or a path to a local copy of it, relative to the
+    /// extension directory. Local repositories are compiled as-is, without checking out `rev`.
     pub repository: String,
     #[serde(alias = "commit")]
     pub rev: String,
     #[serde(default)]
     pub path: Option<String>,
+    /// The path, relative to the extension directory, of a vendored copy of this grammar's
+    /// repository. When set, the grammar is compiled from this directory instead of being
+    /// fetched from its repository, and `path` is relative to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendored_path: Option<PathBuf>,
+    /// The C language standard to compile this grammar with, such as `c11`, for scanners that
+    /// rely on features missing from Clang's default standard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub c_standard: Option<String>,
+    /// The URL of a prebuilt wasm of this grammar, which is downloaded instead of compiling the
+    /// grammar from source.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prebuilt_url: Option<String>,
+    /// Symbols that the compiled grammar should export in addition to `tree_sitter_<name>`, for
+    /// grammars whose scanners have a non-standard interface.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_exports: Vec<String>,
+    /// A source archive to compile this grammar from, instead of checking out its repository.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<GrammarArchive>,
+}
+
+/// A `.tar.gz` archive of a grammar's source, pinned by the hash of its content.
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct GrammarArchive {
+    pub url: String,
+    /// A [Subresource Integrity](https://www.w3.org/TR/SRI/) hash of the archive, such as
+    /// `sha256-<base64 digest>`. The `sha256`, `sha384` and `sha512` algorithms are supported.
+    pub integrity: String,
 }
 
 #[derive(Clone, Default, PartialEq, Eq, Debug, Deserialize, Serialize)]
@@ -218,38 +258,62 @@ pub struct DebugAdapterManifestEntry {
 #[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
 pub struct DebugLocatorManifestEntry {}
 
+#[derive(Clone, PartialEq, Eq, Debug, Deserialize, Serialize)]
+pub struct PostBuildManifestEntry {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
 impl ExtensionManifest {
     pub async fn load(fs: Arc<dyn Fs>, extension_dir: &Path) -> Result<Self> {
+        let mut extension_manifest_path = extension_dir.join("extension.json");
+        if !fs.is_file(&extension_manifest_path).await {
+            extension_manifest_path.set_extension("toml");
+        }
+        Self::load_from_path(fs, extension_dir, &extension_manifest_path).await
+    }
+
+    /// Loads the manifest of the extension in `extension_dir` from `manifest_path`, rather than
+    /// from its standard location. A relative `manifest_path` is resolved against `extension_dir`.
+    ///
+    /// Manifests with a `.json` extension are read as legacy `extension.json` manifests.
+    pub async fn load_from_path(
+        fs: Arc<dyn Fs>,
+        extension_dir: &Path,
+        manifest_path: &Path,
+    ) -> Result<Self> {
         let extension_name = extension_dir
             .file_name()
             .and_then(OsStr::to_str)
             .context("invalid extension name")?;
 
-        let mut extension_manifest_path = extension_dir.join("extension.json");
-        if fs.is_file(&extension_manifest_path).await {
-            let manifest_content = fs
-                .load(&extension_manifest_path)
-                .await
-                .with_context(|| format!("failed to load {extension_name} extension.json"))?;
+        let extension_manifest_path = extension_dir.join(manifest_path);
+        let manifest_file_name = extension_manifest_path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .context("invalid manifest path")?;
+        let manifest_content = fs
+            .load(&extension_manifest_path)
+            .await
+            .with_context(|| format!("failed to load {extension_name} {manifest_file_name}"))?;
+
+        if extension_manifest_path.extension() == Some(OsStr::new("json")) {
             let manifest_json = serde_json::from_str::<OldExtensionManifest>(&manifest_content)
                 .with_context(|| {
-                    format!("invalid extension.json for extension {extension_name}")
+                    format!("invalid {manifest_file_name} for extension {extension_name}")
                 })?;
 
             Ok(manifest_from_old_manifest(manifest_json, extension_name))
         } else {
-            extension_manifest_path.set_extension("toml");
-            let manifest_content = fs
-                .load(&extension_manifest_path)
-                .await
-                .with_context(|| format!("failed to load {extension_name} extension.toml"))?;
-            toml::from_str(&manifest_content)
-                .with_context(|| format!("invalid extension.toml for extension {extension_name}"))
+            toml::from_str(&manifest_content).with_context(|| {
+                format!("invalid {manifest_file_name} for extension {extension_name}")
+            })
         }
     }
 }
 
-fn manifest_from_old_manifest(
+pub(crate) fn manifest_from_old_manifest(
     manifest_json: OldExtensionManifest,
     extension_id: &str,
 ) -> ExtensionManifest {
@@ -288,6 +352,8 @@ fn manifest_from_old_manifest(
         capabilities: Vec::new(),
         debug_adapters: Default::default(),
         debug_locators: Default::default(),
+        min_zed_version: None,
+        post_build: None,
     }
 }
 
@@ -317,6 +383,8 @@ mod tests {
             capabilities: vec![],
             debug_adapters: Default::default(),
             debug_locators: Default::default(),
+            min_zed_version: None,
+            post_build: None,
         }
     }
 
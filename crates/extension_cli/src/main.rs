@@ -2,18 +2,20 @@ use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::Arc;
 
 use ::fs::{CopyOptions, Fs, RealFs, copy_recursive};
 use anyhow::{Context as _, Result, bail};
 use clap::Parser;
 use extension::ExtensionManifest;
-use extension::extension_builder::{CompileExtensionOptions, ExtensionBuilder};
+use extension::extension_builder::{
+    CompileExtensionOptions, ExtensionBuilder, write_extension_archive,
+};
 use language::LanguageConfig;
 use reqwest_client::ReqwestClient;
 use rpc::ExtensionProvides;
 use tree_sitter::{Language, Query, WasmStore};
+use wasmtime::component::{Component, Linker};
 
 #[derive(Parser, Debug)]
 #[command(name = "zed-extension")]
@@ -27,6 +29,23 @@ struct Args {
     /// The path to a directory where build dependencies are downloaded
     #[arg(long)]
     scratch_dir: PathBuf,
+    /// The path to the extension manifest, relative to the extension directory, if it is not
+    /// `extension.toml`
+    #[arg(long)]
+    manifest_path: Option<PathBuf>,
+    /// Whether to instantiate the compiled extension and run its initialization, as a smoke test
+    /// that it can be loaded. Any host functions that it calls while initializing will trap.
+    #[arg(long)]
+    load_test: bool,
+    /// Whether to run the post-build command declared in the extension manifest, if any. The
+    /// command runs arbitrary code from the extension, so only pass this for trusted extensions.
+    #[arg(long)]
+    run_post_build_command: bool,
+    /// Whether to package the extension's themes in a canonical form, with their colors written
+    /// as `#rrggbbaa` and their JSON reformatted. The themes in the extension directory are left
+    /// as they are.
+    #[arg(long)]
+    normalize_themes: bool,
 }
 
 #[tokio::main]
@@ -53,7 +72,11 @@ async fn main() -> Result<()> {
     };
 
     log::info!("loading extension manifest");
-    let mut manifest = ExtensionManifest::load(fs.clone(), &extension_path).await?;
+    let mut manifest = if let Some(manifest_path) = &args.manifest_path {
+        ExtensionManifest::load_from_path(fs.clone(), &extension_path, manifest_path).await?
+    } else {
+        ExtensionManifest::load(fs.clone(), &extension_path).await?
+    };
 
     log::info!("compiling extension");
 
@@ -66,36 +89,49 @@ async fn main() -> Result<()> {
     let http_client = Arc::new(ReqwestClient::user_agent(&user_agent)?);
 
     let builder = ExtensionBuilder::new(http_client, scratch_dir);
-    builder
+    let compile_output = builder
         .compile_extension(
             &extension_path,
             &mut manifest,
-            CompileExtensionOptions { release: true },
+            CompileExtensionOptions {
+                release: true,
+                run_post_build_command: args.run_post_build_command,
+                normalize_themes: args.normalize_themes,
+                ..Default::default()
+            },
         )
         .await
         .context("failed to compile extension")?;
 
+    if args.load_test && manifest.lib.kind.is_some() {
+        load_test_extension(&extension_path).context("failed to load test extension")?;
+    }
+
     let grammars = test_grammars(&manifest, &extension_path, &mut wasm_store)?;
     test_languages(&manifest, &extension_path, &grammars)?;
-    test_themes(&manifest, &extension_path, fs.clone()).await?;
+    let themes_dir = compile_output
+        .normalized_themes_dir
+        .as_deref()
+        .unwrap_or(extension_path.as_path());
+    test_themes(&manifest, themes_dir, fs.clone()).await?;
 
     let archive_dir = output_dir.join("archive");
     fs::remove_dir_all(&archive_dir).ok();
-    copy_extension_resources(&manifest, &extension_path, &archive_dir, fs.clone())
+    copy_extension_resources(
+        &manifest,
+        &extension_path,
+        themes_dir,
+        &archive_dir,
+        fs.clone(),
+    )
+    .await
+    .context("failed to copy extension resources")?;
+
+    let archive_file = fs::File::create(output_dir.join("archive.tar.gz"))
+        .context("failed to create archive.tar.gz")?;
+    write_extension_archive(&archive_dir, archive_file)
         .await
-        .context("failed to copy extension resources")?;
-
-    let tar_output = Command::new("tar")
-        .current_dir(&output_dir)
-        .args(["-czvf", "archive.tar.gz", "-C", "archive", "."])
-        .output()
-        .context("failed to run tar")?;
-    if !tar_output.status.success() {
-        bail!(
-            "failed to create archive.tar.gz: {}",
-            String::from_utf8_lossy(&tar_output.stderr)
-        );
-    }
+        .context("failed to create archive.tar.gz")?;
 
     let extension_provides = extension_provides(&manifest);
 
@@ -159,9 +195,13 @@ fn extension_provides(manifest: &ExtensionManifest) -> BTreeSet<ExtensionProvide
     provides
 }
 
+/// Copies the extension's built artifacts and resources into `output_dir` to be packaged. The
+/// extension's themes are copied from `themes_dir`, which differs from `extension_path` when
+/// normalized themes were written elsewhere.
 async fn copy_extension_resources(
     manifest: &ExtensionManifest,
     extension_path: &Path,
+    themes_dir: &Path,
     output_dir: &Path,
     fs: Arc<dyn Fs>,
 ) -> Result<()> {
@@ -199,7 +239,7 @@ async fn copy_extension_resources(
         fs::create_dir_all(&output_themes_dir)?;
         for theme_path in &manifest.themes {
             fs::copy(
-                extension_path.join(theme_path),
+                themes_dir.join(theme_path),
                 output_themes_dir.join(theme_path.file_name().context("invalid theme path")?),
             )
             .with_context(|| format!("failed to copy theme '{}'", theme_path.display()))?;
@@ -262,6 +302,34 @@ async fn copy_extension_resources(
     Ok(())
 }
 
+/// Instantiates the extension's `extension.wasm` with stubbed imports and runs its
+/// `init-extension` export.
+fn load_test_extension(extension_path: &Path) -> Result<()> {
+    let wasm_bytes = fs::read(extension_path.join("extension.wasm"))?;
+
+    let mut config = wasmtime::Config::new();
+    config.wasm_component_model(true);
+    let engine = wasmtime::Engine::new(&config)?;
+    let component = Component::from_binary(&engine, &wasm_bytes)?;
+
+    let mut linker = Linker::new(&engine);
+    linker.define_unknown_imports_as_traps(&component)?;
+    let mut store = wasmtime::Store::new(&engine, ());
+    let instance = linker
+        .instantiate(&mut store, &component)
+        .context("failed to instantiate extension")?;
+    let init_extension = instance
+        .get_typed_func::<(), ()>(&mut store, "init-extension")
+        .context("extension does not export `init-extension`")?;
+    init_extension
+        .call(&mut store, ())
+        .context("failed to initialize extension")?;
+    init_extension.post_return(&mut store)?;
+
+    log::info!("instantiated extension");
+    Ok(())
+}
+
 fn test_grammars(
     manifest: &ExtensionManifest,
     extension_path: &Path,
@@ -71,7 +71,10 @@ fn wasm_bytes(cx: &TestAppContext, manifest: &mut ExtensionManifest) -> Vec<u8>
         .block(extension_builder.compile_extension(
             &path,
             manifest,
-            CompileExtensionOptions { release: true },
+            CompileExtensionOptions {
+                release: true,
+                ..Default::default()
+            },
         ))
         .unwrap();
     std::fs::read(path.join("extension.wasm")).unwrap()
@@ -140,6 +143,8 @@ fn manifest() -> ExtensionManifest {
         }],
         debug_adapters: Default::default(),
         debug_locators: Default::default(),
+        min_zed_version: None,
+        post_build: None,
     }
 }
 
@@ -40,7 +40,6 @@ use language::{
 };
 use node_runtime::NodeRuntime;
 use project::ContextProviderWithTasks;
-use release_channel::ReleaseChannel;
 use remote::SshRemoteClient;
 use semantic_version::SemanticVersion;
 use serde::{Deserialize, Serialize};
@@ -77,10 +76,7 @@ pub fn schema_version_range() -> RangeInclusive<SchemaVersion> {
 }
 
 /// Returns whether the given extension version is compatible with this version of Zed.
-pub fn is_version_compatible(
-    release_channel: ReleaseChannel,
-    extension_version: &ExtensionMetadata,
-) -> bool {
+pub fn is_version_compatible(extension_version: &ExtensionMetadata) -> bool {
     let schema_version = extension_version.manifest.schema_version.unwrap_or(0);
     if CURRENT_SCHEMA_VERSION.0 < schema_version {
         return false;
@@ -92,7 +88,7 @@ pub fn is_version_compatible(
         .as_ref()
         .and_then(|wasm_api_version| SemanticVersion::from_str(wasm_api_version).ok())
     {
-        if !is_supported_wasm_api_version(release_channel, wasm_api_version) {
+        if !is_supported_wasm_api_version(wasm_api_version) {
             return false;
         }
     }
@@ -528,7 +524,7 @@ impl ExtensionStore {
         cx: &mut Context<Self>,
     ) -> Task<Result<Vec<ExtensionMetadata>>> {
         let schema_versions = schema_version_range();
-        let wasm_api_versions = wasm_api_version_range(ReleaseChannel::global(cx));
+        let wasm_api_versions = wasm_api_version_range();
         let extension_settings = ExtensionSettings::get_global(cx);
         let extension_ids = self
             .extension_index
@@ -777,7 +773,7 @@ impl ExtensionStore {
         log::info!("installing extension {extension_id} latest version");
 
         let schema_versions = schema_version_range();
-        let wasm_api_versions = wasm_api_version_range(ReleaseChannel::global(cx));
+        let wasm_api_versions = wasm_api_version_range();
 
         let Some(url) = self
             .http_client
@@ -940,7 +936,10 @@ impl ExtensionStore {
                         .compile_extension(
                             &extension_source_path,
                             &mut extension_manifest,
-                            CompileExtensionOptions { release: false },
+                            CompileExtensionOptions {
+                                release: false,
+                                ..Default::default()
+                            },
                         )
                         .await
                 }
@@ -1002,7 +1001,10 @@ impl ExtensionStore {
                 .compile_extension(
                     &path,
                     &mut manifest,
-                    CompileExtensionOptions { release: true },
+                    CompileExtensionOptions {
+                        release: true,
+                        ..Default::default()
+                    },
                 )
                 .await
         });
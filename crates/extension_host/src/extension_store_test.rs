@@ -164,6 +164,8 @@ async fn test_extension_store(cx: &mut TestAppContext) {
                         capabilities: Vec::new(),
                         debug_adapters: Default::default(),
                         debug_locators: Default::default(),
+                        min_zed_version: None,
+                        post_build: None,
                     }),
                     dev: false,
                 },
@@ -195,6 +197,8 @@ async fn test_extension_store(cx: &mut TestAppContext) {
                         capabilities: Vec::new(),
                         debug_adapters: Default::default(),
                         debug_locators: Default::default(),
+                        min_zed_version: None,
+                        post_build: None,
                     }),
                     dev: false,
                 },
@@ -371,6 +375,8 @@ async fn test_extension_store(cx: &mut TestAppContext) {
                 capabilities: Vec::new(),
                 debug_adapters: Default::default(),
                 debug_locators: Default::default(),
+                min_zed_version: None,
+                post_build: None,
             }),
             dev: false,
         },
@@ -8,6 +8,7 @@ mod since_v0_4_0;
 mod since_v0_5_0;
 mod since_v0_6_0;
 use dap::DebugRequest;
+pub use extension::wasm_api_version_range;
 use extension::{DebugTaskDefinition, KeyValueStoreDelegate, WorktreeDelegate};
 use gpui::BackgroundExecutor;
 use language::LanguageName;
@@ -21,7 +22,7 @@ use super::{WasmState, wasm_engine};
 use anyhow::{Context as _, Result, anyhow};
 use semantic_version::SemanticVersion;
 use since_v0_6_0 as latest;
-use std::{ops::RangeInclusive, path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc};
 use wasmtime::{
     Store,
     component::{Component, Linker, Resource},
@@ -54,25 +55,8 @@ fn wasi_view(state: &mut WasmState) -> &mut WasmState {
 }
 
 /// Returns whether the given Wasm API version is supported by the Wasm host.
-pub fn is_supported_wasm_api_version(
-    release_channel: ReleaseChannel,
-    version: SemanticVersion,
-) -> bool {
-    wasm_api_version_range(release_channel).contains(&version)
-}
-
-/// Returns the Wasm API version range that is supported by the Wasm host.
-#[inline(always)]
-pub fn wasm_api_version_range(release_channel: ReleaseChannel) -> RangeInclusive<SemanticVersion> {
-    // Note: The release channel can be used to stage a new version of the extension API.
-    let _ = release_channel;
-
-    let max_version = match release_channel {
-        ReleaseChannel::Dev | ReleaseChannel::Nightly => latest::MAX_VERSION,
-        ReleaseChannel::Stable | ReleaseChannel::Preview => latest::MAX_VERSION,
-    };
-
-    since_v0_0_1::MIN_VERSION..=max_version
+pub fn is_supported_wasm_api_version(version: SemanticVersion) -> bool {
+    wasm_api_version_range().contains(&version)
 }
 
 /// Authorizes access to use unreleased versions of the Wasm API, based on the provided [`ReleaseChannel`].
@@ -7,7 +7,6 @@ use fs::Fs;
 use fuzzy::{StringMatch, StringMatchCandidate, match_strings};
 use gpui::{App, DismissEvent, Entity, EventEmitter, Focusable, Task, WeakEntity, prelude::*};
 use picker::{Picker, PickerDelegate};
-use release_channel::ReleaseChannel;
 use semantic_version::SemanticVersion;
 use settings::update_settings_file;
 use ui::{HighlightedLabel, ListItem, ListItemSpacing, prelude::*};
@@ -174,7 +173,7 @@ impl PickerDelegate for ExtensionVersionSelectorDelegate {
         let candidate_id = self.matches[self.selected_index].candidate_id;
         let extension_version = &self.extension_versions[candidate_id];
 
-        if !extension_host::is_version_compatible(ReleaseChannel::global(cx), extension_version) {
+        if !extension_host::is_version_compatible(extension_version) {
             return;
         }
 
@@ -205,13 +204,12 @@ impl PickerDelegate for ExtensionVersionSelectorDelegate {
         ix: usize,
         selected: bool,
         _: &mut Window,
-        cx: &mut Context<Picker<Self>>,
+        _: &mut Context<Picker<Self>>,
     ) -> Option<Self::ListItem> {
         let version_match = &self.matches[ix];
         let extension_version = &self.extension_versions[version_match.candidate_id];
 
-        let is_version_compatible =
-            extension_host::is_version_compatible(ReleaseChannel::global(cx), extension_version);
+        let is_version_compatible = extension_host::is_version_compatible(extension_version);
         let disabled = !is_version_compatible;
 
         Some(